@@ -5,6 +5,7 @@ use std::{collections::HashMap, error::Error};
 use tokio::sync::mpsc::Sender;
 
 pub mod sega;
+mod ws;
 
 // Trait from: https://web3.okx.com/build/docs/waas/dex-integration
 #[async_trait]
@@ -25,7 +26,7 @@ pub trait Dex: Send + Sync {
     async fn listen_new_pool_addresses(
         &self,
         client: &RpcClient,
-        address_tx: Sender<String>,
+        event_tx: Sender<PoolEvent>,
     ) -> Result<(), Box<dyn Error>>;
 
     // To export quote parameters from the pool address
@@ -44,6 +45,79 @@ pub struct PoolMetadata {
     pub extra: HashMap<String, PoolMetadataValue>,
 }
 
+// Where and when a pool event happened
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolEventContext {
+    pub pool_address: String,
+    pub slot: u64,
+    // None for events observed on account state rather than a transaction
+    pub signature: Option<String>,
+}
+
+// Typed events of a pool, amounts in raw units
+#[derive(Clone, Debug, PartialEq)]
+pub enum PoolEvent {
+    Created {
+        context: PoolEventContext,
+        base_mint: String,
+        quote_mint: String,
+    },
+    Swap {
+        context: PoolEventContext,
+        input_mint: String,
+        output_mint: String,
+        // amount the pool received
+        amount_in: u64,
+        // amount the pool paid out
+        amount_out: u64,
+    },
+    Deposit {
+        context: PoolEventContext,
+        // None when the source, such as a logged event, doesn't tell
+        lp_amount: Option<u64>,
+        base_amount: u64,
+        quote_amount: u64,
+    },
+    Withdraw {
+        context: PoolEventContext,
+        lp_amount: Option<u64>,
+        base_amount: u64,
+        quote_amount: u64,
+    },
+    StatusChanged {
+        context: PoolEventContext,
+        // DEX specific status bits
+        status: u8,
+    },
+    ConfigChanged {
+        context: PoolEventContext,
+        config_address: String,
+    },
+    FeesCollected {
+        context: PoolEventContext,
+        base_amount: u64,
+        quote_amount: u64,
+    },
+}
+
+impl PoolEvent {
+    pub fn context(&self) -> &PoolEventContext {
+        match self {
+            PoolEvent::Created { context, .. }
+            | PoolEvent::Swap { context, .. }
+            | PoolEvent::Deposit { context, .. }
+            | PoolEvent::Withdraw { context, .. }
+            | PoolEvent::StatusChanged { context, .. }
+            | PoolEvent::ConfigChanged { context, .. }
+            | PoolEvent::FeesCollected { context, .. } => context,
+        }
+    }
+
+    pub fn pool_address(&self) -> &str {
+        &self.context().pool_address
+    }
+}
+
 // Extended value types for pool
 #[derive(Clone)]
 pub enum PoolMetadataValue {
@@ -1,96 +1,1732 @@
-use anchor_lang::AccountDeserialize;
+use anchor_lang::{AccountDeserialize, AnchorSerialize, Discriminator};
 use anyhow::Context;
 use async_trait::async_trait;
-use futures_util::{sink::SinkExt, StreamExt};
-use log::{error, info};
-use solana_account_decoder::UiAccountEncoding;
+use log::{debug, error, info};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use solana_account_decoder::{UiAccount, UiAccountEncoding, UiDataSliceConfig};
 use solana_client::{
-    rpc_client::RpcClient,
+    rpc_client::{GetConfirmedSignaturesForAddress2Config, RpcClient},
     rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcTransactionConfig},
-    rpc_filter::RpcFilterType,
+    rpc_filter::{Memcmp, RpcFilterType},
 };
 use solana_sdk::{
-    clock::Clock, commitment_config::CommitmentConfig, pubkey::Pubkey, 
-    signature::Signature, sysvar::Sysvar,
+    bs58, commitment_config::CommitmentConfig, message::MessageHeader, pubkey::Pubkey,
+    signature::Signature,
+};
+use solana_transaction_status::{
+    option_serializer::OptionSerializer, parse_accounts::ParsedAccount,
+    EncodedConfirmedTransactionWithStatusMeta, EncodedTransaction,
+    EncodedTransactionWithStatusMeta, UiCompiledInstruction, UiInnerInstructions, UiInstruction,
+    UiLoadedAddresses, UiMessage, UiParsedInstruction, UiTransaction, UiTransactionEncoding,
+    UiTransactionStatusMeta, UiTransactionTokenBalance,
 };
-use solana_transaction_status::{UiTransactionEncoding, EncodedTransaction, UiMessage};
 use spl_token_2022::extension::{
-    transfer_fee::TransferFeeConfig, BaseStateWithExtensions, StateWithExtensionsOwned,
+    transfer_fee::{TransferFee, TransferFeeConfig},
+    BaseStateWithExtensions, StateWithExtensionsOwned,
 };
 use spl_token_2022::state::{Account, Mint};
 use std::sync::{Arc, Mutex};
-use std::{collections::HashMap, error::Error, str::FromStr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    str::FromStr,
+};
 use tokio::sync::mpsc::Sender;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 
-use super::{get_extra, Dex, PoolMetadata, PoolMetadataValue};
+use super::ws::{is_retryable, Backoff, WsConnection};
+use super::{get_extra, Dex, PoolEvent, PoolEventContext, PoolMetadata, PoolMetadataValue};
+
+use sega_cp_swap::{
+    classify_account_extensions, classify_mint_extensions, max_extension_risk,
+    parse_program_invocations, AmmConfig, DepthCalculator, DepthLevel, ExtensionRisk,
+    InstructionDecodeError, LiquidityCalculator, ObservationState, PoolSnapshot, PoolState,
+    PoolUiScales, ProgramInvocation, SegaAccount, SegaEvent, SegaInstruction, SwapEvent, SwapQuote,
+    TradeDirection, Twap, UiAmountScale, UiDepthLevel, UiSwapQuote, WithdrawQuote,
+};
+
+pub struct SegaCPMM {
+    pub config: SegaCPMMConfig,
+}
+
+/// Quoting policy and connection settings of `SegaCPMM`
+#[derive(Clone, Debug)]
+pub struct SegaCPMMConfig {
+    /// Largest deviation of the spot price from the oracle TWAP, in bps,
+    /// before a pool is flagged as manipulated. `None` disables the check.
+    pub max_twap_deviation_bps: Option<u64>,
+    /// Window of the TWAP the spot price is compared with, in seconds
+    pub twap_window: u64,
+    /// Quote zero for pools flagged as manipulated instead of only flagging
+    pub skip_manipulated_pools: bool,
+    /// Quote zero for pools whose mints or vaults carry a dangerous
+    /// Token-2022 extension, such as a transfer hook or a pausable mint
+    pub skip_dangerous_extensions: bool,
+    /// Seconds before the cached AmmConfig accounts are reloaded
+    pub amm_config_refresh_secs: u64,
+    /// Websocket endpoint of the RPC node the listeners subscribe to
+    pub ws_url: String,
+    /// First delay before reconnecting a dropped websocket, in milliseconds.
+    /// Doubles with each failed attempt.
+    pub reconnect_backoff_min_ms: u64,
+    /// Longest delay between reconnect attempts, in milliseconds
+    pub reconnect_backoff_max_ms: u64,
+    /// Seconds of silence before the websocket is pinged, and again before
+    /// it is considered dead
+    pub heartbeat_secs: u64,
+}
+
+impl Default for SegaCPMMConfig {
+    fn default() -> Self {
+        Self {
+            max_twap_deviation_bps: Some(500),
+            twap_window: 300,
+            skip_manipulated_pools: false,
+            skip_dangerous_extensions: false,
+            amm_config_refresh_secs: 60,
+            ws_url: "wss://api.mainnet-beta.solana.com".to_string(),
+            reconnect_backoff_min_ms: 500,
+            reconnect_backoff_max_ms: 30_000,
+            heartbeat_secs: 30,
+        }
+    }
+}
+
+impl Default for SegaCPMM {
+    fn default() -> Self {
+        Self::new(SegaCPMMConfig::default())
+    }
+}
+
+/// A pool of a pair with the quote it gives for the ranked input size
+#[derive(Debug)]
+pub struct PoolRanking {
+    pub pool_address: String,
+    /// Address of the pool's AmmConfig
+    pub amm_config: String,
+    /// Index of the pool's AmmConfig, i.e. its fee tier
+    pub amm_config_index: u16,
+    /// The trade fee, denominated in hundredths of a bip (10^-6)
+    pub trade_fee_rate: u64,
+    pub quote: SwapQuote,
+}
+
+/// Account of a pool tracked by `subscribe_pool_accounts`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PoolAccount {
+    PoolState,
+    Vault0,
+    Vault1,
+}
+
+/// Reserves of a tracked pool after one of its accounts changed
+#[derive(Clone, Debug)]
+pub struct PoolUpdate {
+    pub pool_address: String,
+    /// Slot the account changed in
+    pub slot: u64,
+    /// The account that changed
+    pub account: PoolAccount,
+    /// Vault 0 balance less the accrued fees, `None` if the pool is unquotable
+    pub base_reserve: Option<u64>,
+    /// Vault 1 balance less the accrued fees, `None` if the pool is unquotable
+    pub quote_reserve: Option<u64>,
+}
+
+/// An instruction of the program found in a transaction
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedInstruction {
+    /// Index of the top level instruction that is or invoked it
+    pub instruction_index: usize,
+    /// Position among the inner instructions of that top level instruction,
+    /// `None` for the top level instruction itself
+    pub inner_index: Option<usize>,
+    /// Invocation depth, 1 for top level, when the node reports it
+    pub stack_height: Option<u32>,
+    pub instruction: SegaInstruction,
+}
+
+// Maps accountSubscribe requests and subscriptions to the account they track
+#[derive(Default)]
+struct AccountSubscriptions {
+    requests: Vec<(String, PoolAccount)>,
+    addresses: Vec<Pubkey>,
+    subscriptions: HashMap<u64, (String, PoolAccount)>,
+}
+
+impl AccountSubscriptions {
+    // Subscribe requests for the state and vaults of every known pool, the
+    // request id being the index of the tracked account
+    fn subscribe_messages(&mut self, pool_addresses: &[String]) -> Vec<String> {
+        let pool_address_map = POOL_ADDRESS_MAP.lock().unwrap();
+        let mut messages = Vec::new();
+        for pool_address in pool_addresses {
+            let Some(pool_state) = pool_address_map.get(pool_address) else {
+                error!("Pool {} is not loaded, not subscribing", pool_address);
+                continue;
+            };
+            let Ok(pool_key) = Pubkey::from_str(pool_address) else {
+                error!("Invalid pool address {}, not subscribing", pool_address);
+                continue;
+            };
+            for (account, address) in [
+                (PoolAccount::PoolState, pool_key),
+                (PoolAccount::Vault0, pool_state.token_0_vault),
+                (PoolAccount::Vault1, pool_state.token_1_vault),
+            ] {
+                messages.push(format!(
+                    r#"{{"jsonrpc":"2.0","id":{},"method":"accountSubscribe","params":["{}",{{"encoding":"base64","commitment":"processed"}}]}}"#,
+                    self.requests.len(),
+                    address
+                ));
+                self.requests.push((pool_address.clone(), account));
+                self.addresses.push(address);
+            }
+        }
+        messages
+    }
+
+    // Record a subscribe response, returns `false` for any other message
+    fn confirm(&mut self, message: &serde_json::Value) -> bool {
+        let (Some(id), Some(subscription)) = (
+            message.get("id").and_then(|id| id.as_u64()),
+            message.get("result").and_then(|result| result.as_u64()),
+        ) else {
+            return false;
+        };
+        if let Some(tracked) = self.requests.get(id as usize) {
+            self.subscriptions.insert(subscription, tracked.clone());
+        }
+        true
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref TOKEN_MINT_MAP: Arc<Mutex<HashMap<String, StateWithExtensionsOwned<Mint>>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref POOL_ADDRESS_MAP: Arc<Mutex<HashMap<String, PoolState>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref POOL_METADATA_MAP: Arc<Mutex<HashMap<String, PoolMetadata>>> = Arc::new(Mutex::new(HashMap::new()));
+    // Reserves of each cached pool, vault amounts net of accrued fees
+    static ref POOL_RESERVE_MAP: Arc<Mutex<HashMap<String, (u64, u64)>>> = Arc::new(Mutex::new(HashMap::new()));
+    // Slot of the last applied update of each tracked pool account
+    static ref POOL_ACCOUNT_SLOT_MAP: Arc<Mutex<HashMap<(String, PoolAccount), u64>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref AMM_CONFIG_MAP: Arc<Mutex<HashMap<String, AmmConfig>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref AMM_CONFIG_INDEX_MAP: Arc<Mutex<HashMap<u16, String>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref AMM_CONFIG_REFRESHED_AT: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
+}
+
+impl SegaCPMM {
+    pub fn new(config: SegaCPMMConfig) -> Self {
+        Self { config }
+    }
+
+    fn derive_accounts_from_pool_address(
+        &self,
+        client: &RpcClient,
+        pool_address: &str,
+    ) -> Option<PoolState> {
+        let pool_address = Pubkey::from_str(pool_address).unwrap();
+        if let Some(account) = client
+            .get_account_with_commitment(&pool_address, CommitmentConfig::processed())
+            .ok()?
+            .value
+        {
+            let mut data: &[u8] = &account.data;
+            PoolState::try_deserialize(&mut data).ok()
+        } else {
+            None
+        }
+    }
+
+    fn is_excluded_as_manipulated(&self, metadata: &PoolMetadata) -> bool {
+        let is_manipulated =
+            get_extra!(metadata, "twap_deviation_exceeded", PoolMetadataValue::Bool)
+                .unwrap_or(false);
+        is_manipulated && self.config.skip_manipulated_pools
+    }
+
+    fn is_excluded_by_extensions(&self, metadata: &PoolMetadata) -> bool {
+        let has_dangerous_extension =
+            get_extra!(metadata, "extension_risk", PoolMetadataValue::String)
+                .is_some_and(|risk| risk == ExtensionRisk::Dangerous.to_string());
+        has_dangerous_extension && self.config.skip_dangerous_extensions
+    }
+
+    fn transfer_fee_for_mint(&self, mint: &str, epoch: u64) -> Option<TransferFee> {
+        let token_mint_map = TOKEN_MINT_MAP.lock().unwrap();
+        let mint = token_mint_map.get(mint)?;
+        mint.get_extension::<TransferFeeConfig>()
+            .ok()
+            .map(|transfer_fee_config| *transfer_fee_config.get_epoch_fee(epoch))
+    }
+
+    fn fetch_pool_addresses_with_filters(
+        &self,
+        client: &RpcClient,
+        mut filters: Vec<RpcFilterType>,
+    ) -> anyhow::Result<Vec<String>> {
+        filters.insert(
+            0,
+            RpcFilterType::Memcmp(Memcmp::new_base58_encoded(0, PoolState::DISCRIMINATOR)),
+        );
+        // only the addresses are needed, so skip the account data
+        let account_config = RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            data_slice: Some(UiDataSliceConfig {
+                offset: 0,
+                length: 0,
+            }),
+            ..RpcAccountInfoConfig::default()
+        };
+        let accounts = client.get_program_accounts_with_config(
+            &self.dex_program_id().to_bytes().into(),
+            RpcProgramAccountsConfig {
+                filters: Some(filters),
+                account_config,
+                ..RpcProgramAccountsConfig::default()
+            },
+        )?;
+        Ok(accounts
+            .into_iter()
+            .map(|(pk, _acct)| pk.to_string())
+            .collect())
+    }
+
+    /// Addresses of all pools trading `mint`, on either side
+    pub fn fetch_pools_by_mint(
+        &self,
+        client: &RpcClient,
+        mint: &Pubkey,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut pool_addresses = Vec::new();
+        for offset in [
+            PoolState::TOKEN_0_MINT_OFFSET,
+            PoolState::TOKEN_1_MINT_OFFSET,
+        ] {
+            pool_addresses.extend(self.fetch_pool_addresses_with_filters(
+                client,
+                vec![RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+                    offset,
+                    mint.as_ref(),
+                ))],
+            )?);
+        }
+        Ok(pool_addresses)
+    }
+
+    /// Addresses of all pools trading `mint_a` against `mint_b`, whichever
+    /// of the two is the pool's token_0
+    pub fn fetch_pools_by_pair(
+        &self,
+        client: &RpcClient,
+        mint_a: &Pubkey,
+        mint_b: &Pubkey,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut pool_addresses = Vec::new();
+        for (token_0_mint, token_1_mint) in [(mint_a, mint_b), (mint_b, mint_a)] {
+            pool_addresses.extend(self.fetch_pool_addresses_with_filters(
+                client,
+                vec![
+                    RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+                        PoolState::TOKEN_0_MINT_OFFSET,
+                        token_0_mint.as_ref(),
+                    )),
+                    RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+                        PoolState::TOKEN_1_MINT_OFFSET,
+                        token_1_mint.as_ref(),
+                    )),
+                ],
+            )?);
+            if mint_a == mint_b {
+                break;
+            }
+        }
+        Ok(pool_addresses)
+    }
+
+    /// Load every AmmConfig account of the program into the cache, keyed by
+    /// address and by index. The loaded configs replace the cached ones, so
+    /// configs closed on chain are evicted.
+    pub fn fetch_amm_configs(
+        &self,
+        client: &RpcClient,
+    ) -> anyhow::Result<HashMap<String, AmmConfig>> {
+        let accounts = client.get_program_accounts_with_config(
+            &self.dex_program_id(),
+            RpcProgramAccountsConfig {
+                filters: Some(vec![
+                    RpcFilterType::DataSize(AmmConfig::LEN as u64),
+                    RpcFilterType::Memcmp(Memcmp::new_base58_encoded(0, AmmConfig::DISCRIMINATOR)),
+                ]),
+                account_config: RpcAccountInfoConfig {
+                    encoding: Some(UiAccountEncoding::Base64Zstd),
+                    ..RpcAccountInfoConfig::default()
+                },
+                ..RpcProgramAccountsConfig::default()
+            },
+        )?;
+        let mut amm_configs = HashMap::new();
+        for (address, account) in accounts {
+            let mut data: &[u8] = &account.data;
+            match AmmConfig::try_deserialize(&mut data) {
+                Ok(amm_config) => {
+                    amm_configs.insert(address.to_string(), amm_config);
+                }
+                Err(e) => error!(
+                    "Failed to decode {} AmmConfig {}: {}",
+                    self.dex_name(),
+                    address,
+                    e
+                ),
+            }
+        }
+        self.replace_amm_configs(&amm_configs);
+        *AMM_CONFIG_REFRESHED_AT.lock().unwrap() = Some(Instant::now());
+        Ok(amm_configs)
+    }
+
+    /// The AmmConfig at `address`, from the cache while it is fresh
+    pub fn amm_config(&self, client: &RpcClient, address: &Pubkey) -> Option<AmmConfig> {
+        let is_stale = AMM_CONFIG_REFRESHED_AT
+            .lock()
+            .unwrap()
+            .map_or(true, |refreshed_at| {
+                refreshed_at.elapsed() >= Duration::from_secs(self.config.amm_config_refresh_secs)
+            });
+        if is_stale {
+            if let Err(e) = self.fetch_amm_configs(client) {
+                error!("Failed to fetch {} AmmConfigs: {}", self.dex_name(), e);
+                // keep serving the cached configs until the next refresh
+                // instead of retrying on every call while the RPC is down
+                *AMM_CONFIG_REFRESHED_AT.lock().unwrap() = Some(Instant::now());
+            }
+        }
+        let cached = AMM_CONFIG_MAP
+            .lock()
+            .unwrap()
+            .get(&address.to_string())
+            .cloned();
+        if cached.is_some() {
+            return cached;
+        }
+        let account = client
+            .get_account_with_commitment(address, CommitmentConfig::processed())
+            .ok()?
+            .value?;
+        let mut data: &[u8] = &account.data;
+        let amm_config = AmmConfig::try_deserialize(&mut data).ok()?;
+        self.cache_amm_config(&address.to_string(), amm_config.clone());
+        Some(amm_config)
+    }
+
+    /// The cached AmmConfig with config index `index`
+    pub fn amm_config_by_index(&self, index: u16) -> Option<(String, AmmConfig)> {
+        let address = AMM_CONFIG_INDEX_MAP.lock().unwrap().get(&index)?.clone();
+        let amm_config = AMM_CONFIG_MAP.lock().unwrap().get(&address)?.clone();
+        Some((address, amm_config))
+    }
+
+    fn cache_amm_config(&self, address: &str, amm_config: AmmConfig) {
+        let mut amm_config_map = AMM_CONFIG_MAP.lock().unwrap();
+        if let Some(cached) = amm_config_map.get(address) {
+            self.log_amm_config_change(address, cached, &amm_config);
+        }
+        AMM_CONFIG_INDEX_MAP
+            .lock()
+            .unwrap()
+            .insert(amm_config.index, address.to_string());
+        amm_config_map.insert(address.to_string(), amm_config);
+    }
+
+    fn replace_amm_configs(&self, amm_configs: &HashMap<String, AmmConfig>) {
+        let mut amm_config_map = AMM_CONFIG_MAP.lock().unwrap();
+        for (address, cached) in amm_config_map.iter() {
+            match amm_configs.get(address) {
+                Some(amm_config) => self.log_amm_config_change(address, cached, amm_config),
+                None => info!("{} AmmConfig {} was closed", self.dex_name(), address),
+            }
+        }
+        *AMM_CONFIG_INDEX_MAP.lock().unwrap() = amm_configs
+            .iter()
+            .map(|(address, amm_config)| (amm_config.index, address.clone()))
+            .collect();
+        *amm_config_map = amm_configs.clone();
+    }
+
+    fn log_amm_config_change(&self, address: &str, cached: &AmmConfig, amm_config: &AmmConfig) {
+        if cached.try_to_vec().ok() != amm_config.try_to_vec().ok() {
+            info!(
+                "{} AmmConfig {} changed, trade fee rate {} -> {}",
+                self.dex_name(),
+                address,
+                cached.trade_fee_rate,
+                amm_config.trade_fee_rate
+            );
+        }
+    }
+
+    /// Every tradable pool of the pair, under any AmmConfig, ranked by the
+    /// amount of `output_mint` received for `amount_in` of `input_mint`,
+    /// trade and transfer fees included. Best pool first. Pools `quote`
+    /// would skip, as manipulated or for a dangerous extension, are left out.
+    pub fn rank_pools_for_pair(
+        &self,
+        client: &RpcClient,
+        input_mint: &Pubkey,
+        output_mint: &Pubkey,
+        amount_in: u64,
+    ) -> anyhow::Result<Vec<PoolRanking>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let mut rankings: Vec<PoolRanking> = self
+            .fetch_pools_by_pair(client, input_mint, output_mint)?
+            .iter()
+            .filter_map(|pool_address| {
+                let metadata = self.fetch_pool_metadata(client, pool_address)?;
+                let pool_state = POOL_ADDRESS_MAP.lock().unwrap().get(pool_address)?.clone();
+                if !pool_state.is_tradable(now) {
+                    return None;
+                }
+                if self.is_excluded_as_manipulated(&metadata)
+                    || self.is_excluded_by_extensions(&metadata)
+                {
+                    return None;
+                }
+                let trade_direction = if pool_state.token_0_mint == *input_mint {
+                    TradeDirection::ZeroForOne
+                } else {
+                    TradeDirection::OneForZero
+                };
+                let snapshot = self.pool_snapshot(&metadata)?;
+                let amm_config = self.amm_config(client, &pool_state.amm_config)?;
+                Some(PoolRanking {
+                    pool_address: pool_address.clone(),
+                    amm_config: pool_state.amm_config.to_string(),
+                    amm_config_index: amm_config.index,
+                    trade_fee_rate: snapshot.trade_fee_rate,
+                    quote: snapshot.swap_base_input(trade_direction, amount_in)?,
+                })
+            })
+            .collect();
+        rankings.sort_by(|a, b| {
+            b.quote
+                .amount_received()
+                .cmp(&a.quote.amount_received())
+                .then(a.trade_fee_rate.cmp(&b.trade_fee_rate))
+        });
+        Ok(rankings)
+    }
+
+    /// Metadata of a pool as last loaded by `fetch_pool_metadata` and
+    /// updated by `subscribe_pool_accounts`
+    pub fn cached_pool_metadata(&self, pool_address: &str) -> Option<PoolMetadata> {
+        POOL_METADATA_MAP.lock().unwrap().get(pool_address).cloned()
+    }
+
+    /// Stream changes of the state and vault accounts of pools previously
+    /// loaded with `fetch_pool_metadata`. The cached pool state and metadata
+    /// are updated in place before each update is sent. Dropped connections
+    /// are reopened and resubscribed, after which every tracked account is
+    /// reloaded so changes made while disconnected are not lost. Returns once
+    /// the receiver is dropped, or with an error if the websocket URL can
+    /// never connect.
+    pub async fn subscribe_pool_accounts(
+        &self,
+        client: &RpcClient,
+        pool_addresses: &[String],
+        update_tx: Sender<PoolUpdate>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut backoff = self.backoff();
+        loop {
+            let Some(mut ws) = self.connect_ws(&mut backoff).await? else {
+                if update_tx.is_closed() {
+                    return Ok(());
+                }
+                continue;
+            };
+            let mut subscriptions = AccountSubscriptions::default();
+            let result = async {
+                for subscribe_msg in subscriptions.subscribe_messages(pool_addresses) {
+                    ws.send_text(subscribe_msg).await?;
+                }
+                for update in self.resync_pool_accounts(client, &subscriptions) {
+                    update_tx.send(update).await?;
+                }
+                loop {
+                    let Some(text) = ws.next_text().await? else {
+                        break;
+                    };
+                    backoff.reset();
+                    let notification: serde_json::Value = serde_json::from_str(&text)?;
+                    if subscriptions.confirm(&notification) {
+                        continue;
+                    }
+                    if let Some(update) =
+                        self.apply_account_notification(&subscriptions, &notification)
+                    {
+                        update_tx.send(update).await?;
+                    }
+                }
+                Ok::<(), Box<dyn Error>>(())
+            }
+            .await;
+            if update_tx.is_closed() {
+                return Ok(());
+            }
+            tokio::time::sleep(self.reconnect_delay(&mut backoff, result)).await;
+        }
+    }
+
+    // Reload every tracked account, applying and reporting its current state
+    fn resync_pool_accounts(
+        &self,
+        client: &RpcClient,
+        subscriptions: &AccountSubscriptions,
+    ) -> Vec<PoolUpdate> {
+        let mut updates = Vec::new();
+        for (requests, addresses) in subscriptions
+            .requests
+            .chunks(100)
+            .zip(subscriptions.addresses.chunks(100))
+        {
+            let response = match client
+                .get_multiple_accounts_with_commitment(addresses, CommitmentConfig::processed())
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Failed to reload {} pool accounts: {}", self.dex_name(), e);
+                    continue;
+                }
+            };
+            for ((pool_address, account), data) in requests.iter().zip(response.value) {
+                let Some(data) = data else {
+                    continue;
+                };
+                if let Some((base_reserve, quote_reserve)) = self.apply_account_update(
+                    pool_address,
+                    *account,
+                    response.context.slot,
+                    &data.data,
+                ) {
+                    updates.push(PoolUpdate {
+                        pool_address: pool_address.clone(),
+                        slot: response.context.slot,
+                        account: *account,
+                        base_reserve,
+                        quote_reserve,
+                    });
+                }
+            }
+        }
+        updates
+    }
+
+    /// Stream a `PoolEvent::Created` for each pool the first time its
+    /// PoolState account is written, as seen by a programSubscribe on the
+    /// program. Pools that exist when the subscription starts are not
+    /// reported. Dropped connections are reopened and resubscribed, after
+    /// which pools created while disconnected are reported too. Returns once
+    /// the receiver is dropped, or with an error if the websocket URL can
+    /// never connect.
+    pub async fn subscribe_new_pools(
+        &self,
+        client: &RpcClient,
+        event_tx: Sender<PoolEvent>,
+    ) -> Result<(), Box<dyn Error>> {
+        // the same discriminator filter as the subscription
+        let mut known_pools: HashSet<String> = self
+            .fetch_pool_addresses_with_filters(client, Vec::new())?
+            .into_iter()
+            .collect();
+        let mut backoff = self.backoff();
+        loop {
+            let Some(mut ws) = self.connect_ws(&mut backoff).await? else {
+                if event_tx.is_closed() {
+                    return Ok(());
+                }
+                continue;
+            };
+            let result = async {
+                ws.send_text(self.program_subscribe_message()).await?;
+                // subscribed first, so no pool created since the pools were
+                // last listed, before connecting or while disconnected, falls
+                // between the two
+                for event in self.missed_pool_creations(client, &mut known_pools)? {
+                    event_tx.send(event).await?;
+                }
+                loop {
+                    let Some(text) = ws.next_text().await? else {
+                        break;
+                    };
+                    backoff.reset();
+                    let notification: serde_json::Value = serde_json::from_str(&text)?;
+                    if let Some(event) =
+                        self.apply_program_notification(&mut known_pools, &notification)
+                    {
+                        info!(
+                            "Detected new {} pool address: {}",
+                            self.dex_name(),
+                            event.pool_address()
+                        );
+                        event_tx.send(event).await?;
+                    }
+                }
+                Ok::<(), Box<dyn Error>>(())
+            }
+            .await;
+            if event_tx.is_closed() {
+                return Ok(());
+            }
+            tokio::time::sleep(self.reconnect_delay(&mut backoff, result)).await;
+        }
+    }
+
+    fn backoff(&self) -> Backoff {
+        Backoff::new(
+            Duration::from_millis(self.config.reconnect_backoff_min_ms),
+            Duration::from_millis(self.config.reconnect_backoff_max_ms),
+        )
+    }
+
+    fn heartbeat(&self) -> Duration {
+        Duration::from_secs(self.config.heartbeat_secs)
+    }
+
+    // Open the websocket. A failed attempt worth retrying is logged and
+    // waited out with `backoff`, giving `None`, others are returned.
+    async fn connect_ws(
+        &self,
+        backoff: &mut Backoff,
+    ) -> Result<Option<WsConnection>, Box<dyn Error>> {
+        match WsConnection::connect(&self.config.ws_url, self.heartbeat()).await {
+            Ok(ws) => Ok(Some(ws)),
+            Err(e) if is_retryable(&e) => {
+                let delay = backoff.next_delay();
+                error!(
+                    "Failed to connect to {} websocket {}: {}, retrying in {:?}",
+                    self.dex_name(),
+                    self.config.ws_url,
+                    e,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Log why a connection ended and how long until the next one
+    fn reconnect_delay(
+        &self,
+        backoff: &mut Backoff,
+        result: Result<(), Box<dyn Error>>,
+    ) -> Duration {
+        let delay = backoff.next_delay();
+        match result {
+            Ok(()) => info!(
+                "{} websocket closed, reconnecting in {:?}",
+                self.dex_name(),
+                delay
+            ),
+            Err(e) => error!(
+                "{} websocket failed: {}, reconnecting in {:?}",
+                self.dex_name(),
+                e,
+                delay
+            ),
+        }
+        delay
+    }
+
+    // The program's newest confirmed transaction, where a backfill starts
+    fn newest_signature(&self, client: &RpcClient) -> Option<Signature> {
+        let page = match client.get_signatures_for_address_with_config(
+            &self.dex_program_id(),
+            GetConfirmedSignaturesForAddress2Config {
+                limit: Some(1),
+                commitment: Some(CommitmentConfig::confirmed()),
+                ..GetConfirmedSignaturesForAddress2Config::default()
+            },
+        ) {
+            Ok(page) => page,
+            Err(e) => {
+                error!(
+                    "Failed to fetch the newest {} signature: {}",
+                    self.dex_name(),
+                    e
+                );
+                return None;
+            }
+        };
+        Signature::from_str(&page.first()?.signature).ok()
+    }
+
+    // Signatures of the program's transactions after `until`, oldest first
+    fn missed_signatures(&self, client: &RpcClient, until: &Signature) -> Vec<Signature> {
+        let mut signatures = Vec::new();
+        let mut before = None;
+        loop {
+            let page = match client.get_signatures_for_address_with_config(
+                &self.dex_program_id(),
+                GetConfirmedSignaturesForAddress2Config {
+                    before,
+                    until: Some(*until),
+                    limit: None,
+                    commitment: Some(CommitmentConfig::confirmed()),
+                },
+            ) {
+                Ok(page) => page,
+                Err(e) => {
+                    error!("Failed to backfill {} signatures: {}", self.dex_name(), e);
+                    break;
+                }
+            };
+            let Some(oldest) = page.last() else {
+                break;
+            };
+            before = Signature::from_str(&oldest.signature).ok();
+            signatures.extend(
+                page.iter()
+                    .filter(|status| status.err.is_none())
+                    .filter_map(|status| Signature::from_str(&status.signature).ok()),
+            );
+            if before.is_none() {
+                break;
+            }
+        }
+        signatures.reverse();
+        signatures
+    }
+
+    // Report the events of a confirmed transaction. Only a closed channel is
+    // an error; transactions that can't be loaded are logged and skipped.
+    async fn process_signature(
+        &self,
+        client: &RpcClient,
+        tx_sig: &Signature,
+        event_tx: &Sender<PoolEvent>,
+    ) -> Result<(), Box<dyn Error>> {
+        let tx = match client.get_transaction_with_config(
+            tx_sig,
+            RpcTransactionConfig {
+                encoding: Some(UiTransactionEncoding::Json),
+                commitment: Some(CommitmentConfig::confirmed()),
+                max_supported_transaction_version: Some(0),
+            },
+        ) {
+            Ok(tx) => tx,
+            Err(e) => {
+                error!("Failed to fetch transaction {}: {}", tx_sig, e);
+                return Ok(());
+            }
+        };
+        for event in self.transaction_events(client, tx_sig, &tx) {
+            event_tx.send(event).await?;
+        }
+        Ok(())
+    }
+
+    // Events of the pools a transaction created or changed, told apart by the
+    // program's instruction logs and the balance changes of the pool vaults
+    // and LP mints
+    fn transaction_events(
+        &self,
+        client: &RpcClient,
+        tx_sig: &Signature,
+        tx: &EncodedConfirmedTransactionWithStatusMeta,
+    ) -> Vec<PoolEvent> {
+        let Some(meta) = &tx.transaction.meta else {
+            return Vec::new();
+        };
+        if meta.err.is_some() {
+            return Vec::new();
+        }
+        let logs: Vec<String> = Option::from(meta.log_messages.clone()).unwrap_or_default();
+        let invocations: Vec<ProgramInvocation> = parse_program_invocations(&logs)
+            .into_iter()
+            .filter(|invocation| invocation.succeeded)
+            .collect();
+        let instructions: Vec<String> = invocations
+            .iter()
+            .filter_map(|invocation| invocation.instruction.clone())
+            .collect();
+        let swap_events: Vec<SwapEvent> = invocations
+            .iter()
+            .flat_map(|invocation| invocation.events())
+            .filter_map(|event| match event {
+                SegaEvent::Swap(event) => Some(event),
+                SegaEvent::LpChange(_) => None,
+            })
+            .collect();
+        let has_instruction =
+            |name: &str| instructions.iter().any(|instruction| instruction == name);
+        let accounts = transaction_accounts(&tx.transaction);
+        let account_keys: Vec<Pubkey> = accounts.iter().map(|account| account.pubkey).collect();
+        // the program writes every pool and config it changes
+        let writable_keys: Vec<Pubkey> = accounts
+            .iter()
+            .filter(|account| account.writable)
+            .map(|account| account.pubkey)
+            .collect();
+        let context = |pool_address: String| PoolEventContext {
+            pool_address,
+            slot: tx.slot,
+            signature: Some(tx_sig.to_string()),
+        };
+        let mut events = Vec::new();
+
+        let mut created = Vec::new();
+        if has_instruction("Initialize") {
+            for (pool_address, pool_state) in self.load_new_pools(client, &writable_keys) {
+                info!(
+                    "Detected new {} pool address: {}",
+                    self.dex_name(),
+                    pool_address
+                );
+                events.push(PoolEvent::Created {
+                    context: context(pool_address.to_string()),
+                    base_mint: pool_state.token_0_mint.to_string(),
+                    quote_mint: pool_state.token_1_mint.to_string(),
+                });
+                created.push(pool_address);
+            }
+        }
+
+        let balance_deltas = token_balance_deltas(meta, &account_keys);
+        let account_delta = |account: &Pubkey| -> i128 {
+            balance_deltas
+                .iter()
+                .filter(|(key, _, _)| key == account)
+                .map(|(_, _, delta)| delta)
+                .sum()
+        };
+        let mint_delta = |mint: &Pubkey| -> i128 {
+            let mint = mint.to_string();
+            balance_deltas
+                .iter()
+                .filter(|(_, balance_mint, _)| *balance_mint == mint)
+                .map(|(_, _, delta)| delta)
+                .sum()
+        };
+        let amount = |delta: i128| u64::try_from(delta.unsigned_abs()).unwrap_or(u64::MAX);
+        let pools: Vec<(Pubkey, PoolState)> = {
+            let pool_address_map = POOL_ADDRESS_MAP.lock().unwrap();
+            writable_keys
+                .iter()
+                .filter(|key| !created.contains(key))
+                .filter_map(|key| Some((*key, pool_address_map.get(&key.to_string())?.clone())))
+                .collect()
+        };
+        for (pool_address, pool_state) in pools {
+            let delta_0 = account_delta(&pool_state.token_0_vault);
+            let delta_1 = account_delta(&pool_state.token_1_vault);
+            let lp_delta = mint_delta(&pool_state.lp_mint);
+            let (mint_0, mint_1) = (
+                pool_state.token_0_mint.to_string(),
+                pool_state.token_1_mint.to_string(),
+            );
+            let context = || context(pool_address.to_string());
+            if lp_delta > 0 {
+                events.push(PoolEvent::Deposit {
+                    context: context(),
+                    lp_amount: Some(amount(lp_delta)),
+                    base_amount: amount(delta_0),
+                    quote_amount: amount(delta_1),
+                });
+            } else if lp_delta < 0 {
+                events.push(PoolEvent::Withdraw {
+                    context: context(),
+                    lp_amount: Some(amount(lp_delta)),
+                    base_amount: amount(delta_0),
+                    quote_amount: amount(delta_1),
+                });
+            } else if (delta_0 > 0 && delta_1 < 0) || (delta_1 > 0 && delta_0 < 0) {
+                let (direction, input_mint, output_mint, delta_in, delta_out) = if delta_0 > 0 {
+                    (TradeDirection::ZeroForOne, mint_0, mint_1, delta_0, delta_1)
+                } else {
+                    (TradeDirection::OneForZero, mint_1, mint_0, delta_1, delta_0)
+                };
+                // a lone logged swap is applied in the direction the vault
+                // balances tell, so the cached reserves catch up and the
+                // pool's next swap events match them again
+                let logged: Vec<&SwapEvent> = swap_events
+                    .iter()
+                    .filter(|event| event.pool_id == pool_address)
+                    .collect();
+                let applied = match logged.as_slice() {
+                    [event] => self.apply_event(
+                        context(),
+                        &SegaEvent::Swap((*event).clone()),
+                        Some(direction),
+                    ),
+                    _ => None,
+                };
+                events.push(applied.unwrap_or_else(|| PoolEvent::Swap {
+                    context: context(),
+                    input_mint,
+                    output_mint,
+                    amount_in: amount(delta_in),
+                    amount_out: amount(delta_out),
+                }));
+            } else if delta_0 <= 0 && delta_1 <= 0 && (delta_0, delta_1) != (0, 0) {
+                // only the protocol and fund fee collections drain both vaults
+                events.push(PoolEvent::FeesCollected {
+                    context: context(),
+                    base_amount: amount(delta_0),
+                    quote_amount: amount(delta_1),
+                });
+            }
+
+            if has_instruction("UpdatePoolStatus") {
+                if let Some(updated) =
+                    self.derive_accounts_from_pool_address(client, &pool_address.to_string())
+                {
+                    if updated.status != pool_state.status {
+                        events.push(PoolEvent::StatusChanged {
+                            context: context(),
+                            status: updated.status,
+                        });
+                    }
+                    POOL_ADDRESS_MAP
+                        .lock()
+                        .unwrap()
+                        .insert(pool_address.to_string(), updated);
+                }
+            }
+        }
+
+        if has_instruction("UpdateAmmConfig") {
+            let config_addresses: Vec<Pubkey> = {
+                let amm_config_map = AMM_CONFIG_MAP.lock().unwrap();
+                writable_keys
+                    .iter()
+                    .filter(|key| amm_config_map.contains_key(&key.to_string()))
+                    .copied()
+                    .collect()
+            };
+            if !config_addresses.is_empty() {
+                if let Err(e) = self.fetch_amm_configs(client) {
+                    error!("Failed to fetch {} AmmConfigs: {}", self.dex_name(), e);
+                }
+            }
+            let pool_address_map = POOL_ADDRESS_MAP.lock().unwrap();
+            for config_address in config_addresses {
+                for (pool_address, _) in pool_address_map
+                    .iter()
+                    .filter(|(_, pool_state)| pool_state.amm_config == config_address)
+                {
+                    events.push(PoolEvent::ConfigChanged {
+                        context: context(pool_address.clone()),
+                        config_address: config_address.to_string(),
+                    });
+                }
+            }
+        }
+        events
+    }
+
+    /// Decode the program's instructions in a transaction, top level ones and
+    /// those invoked through CPI, in execution order. Instructions that don't
+    /// resolve or decode are logged and skipped.
+    pub fn decode_instructions(
+        &self,
+        tx: &EncodedConfirmedTransactionWithStatusMeta,
+    ) -> Vec<DecodedInstruction> {
+        let account_keys: Vec<Pubkey> = transaction_accounts(&tx.transaction)
+            .iter()
+            .map(|account| account.pubkey)
+            .collect();
+        let (signature, top_level): (String, Vec<UiInstruction>) = match &tx.transaction.transaction
+        {
+            EncodedTransaction::Json(transaction) => (
+                transaction.signatures.first().cloned().unwrap_or_default(),
+                match &transaction.message {
+                    UiMessage::Parsed(message) => message.instructions.clone(),
+                    UiMessage::Raw(message) => message
+                        .instructions
+                        .iter()
+                        .cloned()
+                        .map(UiInstruction::Compiled)
+                        .collect(),
+                },
+            ),
+            encoded => {
+                let Some(transaction) = encoded.decode() else {
+                    error!("Failed to decode the message of a transaction");
+                    return Vec::new();
+                };
+                (
+                    transaction
+                        .signatures
+                        .first()
+                        .map(|signature| signature.to_string())
+                        .unwrap_or_default(),
+                    transaction
+                        .message
+                        .instructions()
+                        .iter()
+                        .map(|instruction| {
+                            UiInstruction::Compiled(UiCompiledInstruction::from(instruction, None))
+                        })
+                        .collect(),
+                )
+            }
+        };
+        let inner_instructions: Vec<UiInnerInstructions> = tx
+            .transaction
+            .meta
+            .as_ref()
+            .and_then(|meta| Option::from(meta.inner_instructions.clone()))
+            .unwrap_or_default();
+
+        let program_id = self.dex_program_id();
+        let mut decoded = Vec::new();
+        let mut decode = |instruction: &UiInstruction, instruction_index, inner_index| {
+            // the node only parses instructions of programs it knows
+            if let UiInstruction::Parsed(UiParsedInstruction::Parsed(_)) = instruction {
+                return;
+            }
+            let Some(raw) = RawInstruction::resolve(instruction, &account_keys) else {
+                error!(
+                    "Failed to resolve the accounts of instruction {} of {}",
+                    instruction_index, signature
+                );
+                return;
+            };
+            if raw.program_id != program_id {
+                return;
+            }
+            match SegaInstruction::decode(&raw.data, &raw.accounts) {
+                Ok(instruction) => decoded.push(DecodedInstruction {
+                    instruction_index,
+                    inner_index,
+                    stack_height: raw.stack_height,
+                    instruction,
+                }),
+                Err(e @ InstructionDecodeError::AdminInstruction(_)) => debug!(
+                    "Skipping {} instruction {} of {}: {}",
+                    self.dex_name(),
+                    instruction_index,
+                    signature,
+                    e
+                ),
+                Err(e) => error!(
+                    "Failed to decode {} instruction {} of {}: {}",
+                    self.dex_name(),
+                    instruction_index,
+                    signature,
+                    e
+                ),
+            }
+        };
+        for (instruction_index, instruction) in top_level.iter().enumerate() {
+            decode(instruction, instruction_index, None);
+            for inner in inner_instructions
+                .iter()
+                .filter(|inner| usize::from(inner.index) == instruction_index)
+            {
+                for (inner_index, instruction) in inner.instructions.iter().enumerate() {
+                    decode(instruction, instruction_index, Some(inner_index));
+                }
+            }
+        }
+        decoded
+    }
+
+    // Load and cache the pools among `account_keys` not seen yet
+    fn load_new_pools(
+        &self,
+        client: &RpcClient,
+        account_keys: &[Pubkey],
+    ) -> Vec<(Pubkey, PoolState)> {
+        let unknown: Vec<Pubkey> = {
+            let pool_address_map = POOL_ADDRESS_MAP.lock().unwrap();
+            account_keys
+                .iter()
+                .filter(|key| !pool_address_map.contains_key(&key.to_string()))
+                .copied()
+                .collect()
+        };
+        let program_id = self.dex_program_id();
+        let mut pools = Vec::new();
+        for keys in unknown.chunks(100) {
+            let accounts = match client.get_multiple_accounts(keys) {
+                Ok(accounts) => accounts,
+                Err(e) => {
+                    error!("Failed to fetch {} accounts: {}", self.dex_name(), e);
+                    continue;
+                }
+            };
+            for (key, account) in keys.iter().zip(accounts) {
+                let Some(account) = account.filter(|account| account.owner == program_id) else {
+                    continue;
+                };
+                if let Ok(SegaAccount::PoolState(pool_state)) = SegaAccount::decode(&account.data) {
+                    POOL_ADDRESS_MAP
+                        .lock()
+                        .unwrap()
+                        .insert(key.to_string(), pool_state.clone());
+                    pools.push((*key, pool_state));
+                }
+            }
+        }
+        pools
+    }
+
+    fn program_subscribe_message(&self) -> String {
+        let filters = vec![
+            RpcFilterType::DataSize(PoolState::LEN as u64),
+            RpcFilterType::Memcmp(Memcmp::new_base58_encoded(0, PoolState::DISCRIMINATOR)),
+        ];
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "programSubscribe",
+            "params": [
+                self.dex_program_id().to_string(),
+                {
+                    "encoding": "base64",
+                    "commitment": "confirmed",
+                    "filters": filters,
+                },
+            ],
+        })
+        .to_string()
+    }
+
+    // Creations of the program's pools missing from `known_pools`, loading
+    // and caching their PoolState
+    fn missed_pool_creations(
+        &self,
+        client: &RpcClient,
+        known_pools: &mut HashSet<String>,
+    ) -> anyhow::Result<Vec<PoolEvent>> {
+        let mut events = Vec::new();
+        for pool_address in self.fetch_pool_addresses_with_filters(client, Vec::new())? {
+            if known_pools.contains(&pool_address) {
+                continue;
+            }
+            let response = client.get_account_with_commitment(
+                &Pubkey::from_str(&pool_address)?,
+                CommitmentConfig::confirmed(),
+            )?;
+            let Some(account) = response.value else {
+                continue;
+            };
+            let mut data: &[u8] = &account.data;
+            let Ok(pool_state) = PoolState::try_deserialize(&mut data) else {
+                continue;
+            };
+            let Some(event) = pool_created_event(&pool_address, response.context.slot, &pool_state)
+            else {
+                continue;
+            };
+            info!(
+                "Detected new {} pool address while unsubscribed: {}",
+                self.dex_name(),
+                pool_address
+            );
+            POOL_ADDRESS_MAP
+                .lock()
+                .unwrap()
+                .insert(pool_address.clone(), pool_state);
+            known_pools.insert(pool_address);
+            events.push(event);
+        }
+        Ok(events)
+    }
+
+    // Cache a PoolState pushed by programSubscribe, returning its creation
+    // if the pool isn't in `known_pools` yet
+    fn apply_program_notification(
+        &self,
+        known_pools: &mut HashSet<String>,
+        notification: &serde_json::Value,
+    ) -> Option<PoolEvent> {
+        if notification.get("method")?.as_str()? != "programNotification" {
+            return None;
+        }
+        let result = notification.get("params")?.get("result")?;
+        let slot = result.get("context")?.get("slot")?.as_u64()?;
+        let value = result.get("value")?;
+        let pool_address = value.get("pubkey")?.as_str()?;
+        let ui_account: UiAccount = serde_json::from_value(value.get("account")?.clone()).ok()?;
+        let SegaAccount::PoolState(pool_state) =
+            SegaAccount::decode(&ui_account.data.decode()?).ok()?
+        else {
+            return None;
+        };
+        let event = pool_created_event(pool_address, slot, &pool_state)?;
+        POOL_ADDRESS_MAP
+            .lock()
+            .unwrap()
+            .insert(pool_address.to_string(), pool_state);
+        known_pools
+            .insert(pool_address.to_string())
+            .then_some(event)
+    }
+
+    fn apply_account_notification(
+        &self,
+        subscriptions: &AccountSubscriptions,
+        notification: &serde_json::Value,
+    ) -> Option<PoolUpdate> {
+        if notification.get("method")?.as_str()? != "accountNotification" {
+            return None;
+        }
+        let params = notification.get("params")?;
+        let subscription = params.get("subscription")?.as_u64()?;
+        let (pool_address, account) = subscriptions.subscriptions.get(&subscription)?;
+        let result = params.get("result")?;
+        let slot = result.get("context")?.get("slot")?.as_u64()?;
+        let ui_account: UiAccount = serde_json::from_value(result.get("value")?.clone()).ok()?;
+        let data = ui_account.data.decode()?;
+        let (base_reserve, quote_reserve) =
+            self.apply_account_update(pool_address, *account, slot, &data)?;
+        Some(PoolUpdate {
+            pool_address: pool_address.clone(),
+            slot,
+            account: *account,
+            base_reserve,
+            quote_reserve,
+        })
+    }
+
+    // Apply new account data of a tracked pool to the cached pool state and
+    // metadata, returning the resulting reserves. Data older than the last
+    // applied, as when a resync races the notifications, is dropped.
+    fn apply_account_update(
+        &self,
+        pool_address: &str,
+        account: PoolAccount,
+        slot: u64,
+        data: &[u8],
+    ) -> Option<(Option<u64>, Option<u64>)> {
+        {
+            let mut pool_account_slot_map = POOL_ACCOUNT_SLOT_MAP.lock().unwrap();
+            let last_slot = pool_account_slot_map
+                .entry((pool_address.to_string(), account))
+                .or_default();
+            if slot < *last_slot {
+                return None;
+            }
+            *last_slot = slot;
+        }
+        let mut pool_address_map = POOL_ADDRESS_MAP.lock().unwrap();
+        let mut pool_metadata_map = POOL_METADATA_MAP.lock().unwrap();
+        let metadata = pool_metadata_map.get_mut(pool_address)?;
+        match account {
+            PoolAccount::PoolState => {
+                let mut data = data;
+                let pool_state = PoolState::try_deserialize(&mut data).ok()?;
+                metadata.extra.insert(
+                    "is_trading".to_string(),
+                    PoolMetadataValue::Bool(pool_state.pool_status().can_swap()),
+                );
+                metadata.extra.insert(
+                    "open_time".to_string(),
+                    PoolMetadataValue::Number(pool_state.open_time as f64),
+                );
+                metadata.extra.insert(
+                    "lp_supply".to_string(),
+                    PoolMetadataValue::Number(pool_state.lp_supply as f64),
+                );
+                pool_address_map.insert(pool_address.to_string(), pool_state);
+            }
+            PoolAccount::Vault0 | PoolAccount::Vault1 => {
+                let vault = StateWithExtensionsOwned::<Account>::unpack(data.to_vec()).ok()?;
+                let (amount_key, frozen_key) = if account == PoolAccount::Vault0 {
+                    ("vault_0_amount", "vault_0_frozen")
+                } else {
+                    ("vault_1_amount", "vault_1_frozen")
+                };
+                metadata.extra.insert(
+                    amount_key.to_string(),
+                    PoolMetadataValue::Number(vault.base.amount as f64),
+                );
+                metadata.extra.insert(
+                    frozen_key.to_string(),
+                    PoolMetadataValue::Bool(vault.base.is_frozen()),
+                );
+            }
+        }
+
+        let pool_state = pool_address_map.get(pool_address)?;
+        let vault_0_amount = get_extra!(metadata, "vault_0_amount", PoolMetadataValue::Number)?;
+        let vault_1_amount = get_extra!(metadata, "vault_1_amount", PoolMetadataValue::Number)?;
+        // like fetch_pool_metadata, a frozen vault leaves nothing to trade
+        let vault_frozen = get_extra!(metadata, "vault_0_frozen", PoolMetadataValue::Bool)
+            .unwrap_or(false)
+            || get_extra!(metadata, "vault_1_frozen", PoolMetadataValue::Bool).unwrap_or(false);
+        let (base_reserve, quote_reserve) = match pool_state
+            .checked_vault_amount_without_fee(vault_0_amount as u64, vault_1_amount as u64)
+        {
+            Ok(_) if vault_frozen => {
+                error!(
+                    "{} pool {} is unquotable: a vault is frozen",
+                    self.dex_name(),
+                    pool_address
+                );
+                (None, None)
+            }
+            Ok((base_reserve, quote_reserve)) => (Some(base_reserve), Some(quote_reserve)),
+            Err(e) => {
+                error!(
+                    "{} pool {} is unquotable: {}",
+                    self.dex_name(),
+                    pool_address,
+                    e
+                );
+                (None, None)
+            }
+        };
+        cache_reserves(pool_address, base_reserve.zip(quote_reserve));
+        metadata.base_reserve = base_reserve.map(|v| v as f64);
+        metadata.quote_reserve = quote_reserve.map(|v| v as f64);
+        Some((base_reserve, quote_reserve))
+    }
+
+    // Events of a transaction as told by the events it logged, applying
+    // their reserve changes to the cache. None when the logs don't tell all
+    // the transaction did, e.g. instructions that emit no event or pools
+    // that aren't cached, so the caller falls back to getTransaction.
+    fn log_events(&self, tx_sig: &Signature, slot: u64, logs: &[String]) -> Option<Vec<PoolEvent>> {
+        let invocations: Vec<ProgramInvocation> = parse_program_invocations(logs)
+            .into_iter()
+            .filter(|invocation| invocation.succeeded)
+            .collect();
+        if invocations.is_empty() {
+            return None;
+        }
+        let mut events = Vec::new();
+        for invocation in invocations {
+            let program_events = invocation.events();
+            match invocation.instruction.as_deref() {
+                Some("SwapBaseInput" | "SwapBaseOutput" | "Deposit" | "Withdraw")
+                    if !program_events.is_empty() => {}
+                _ => return None,
+            }
+            for event in program_events {
+                let context = PoolEventContext {
+                    pool_address: event.pool_id().to_string(),
+                    slot,
+                    signature: Some(tx_sig.to_string()),
+                };
+                events.push(self.apply_event(context, &event, None)?);
+            }
+        }
+        Some(events)
+    }
+
+    // Apply the reserves an event reports to the cached pool. Swap events
+    // don't name their mints, so unless `trade_direction` is known from the
+    // transaction the direction is the one whose reserves before or after
+    // the swap match the cached ones: account updates may have cached the
+    // latter already. Logs don't tell how many LP tokens a deposit or
+    // withdrawal moved, so the LP supply is left to the PoolState account
+    // updates.
+    fn apply_event(
+        &self,
+        context: PoolEventContext,
+        event: &SegaEvent,
+        trade_direction: Option<TradeDirection>,
+    ) -> Option<PoolEvent> {
+        let pool_address = context.pool_address.clone();
+        let config_address = POOL_ADDRESS_MAP
+            .lock()
+            .unwrap()
+            .get(&pool_address)?
+            .amm_config;
+        let amm_config = AMM_CONFIG_MAP
+            .lock()
+            .unwrap()
+            .get(&config_address.to_string())?
+            .clone();
+        let mut pool_address_map = POOL_ADDRESS_MAP.lock().unwrap();
+        let mut pool_metadata_map = POOL_METADATA_MAP.lock().unwrap();
+        let pool_state = pool_address_map.get_mut(&context.pool_address)?;
+        let metadata = pool_metadata_map.get_mut(&context.pool_address)?;
+
+        let (pool_event, base_reserve, quote_reserve) = match event {
+            SegaEvent::Swap(event) => {
+                let reserves = POOL_RESERVE_MAP.lock().unwrap().get(&pool_address).copied();
+                let (trade_direction, is_applied) = match trade_direction {
+                    Some(trade_direction) => {
+                        let (input_reserve, output_reserve) = event.reserves_after(&amm_config)?;
+                        let after = match trade_direction {
+                            TradeDirection::ZeroForOne => (input_reserve, output_reserve),
+                            TradeDirection::OneForZero => (output_reserve, input_reserve),
+                        };
+                        (trade_direction, reserves == Some(after))
+                    }
+                    None => event.trade_direction(&amm_config, reserves?)?,
+                };
+                // cached reserves from after the swap come with the fees it
+                // accrued in the cached PoolState
+                let (protocol_fee, fund_fee) = if is_applied {
+                    (0, 0)
+                } else {
+                    event.accrued_fees(&amm_config)?
+                };
+                let (input_reserve, output_reserve) = event.reserves_after(&amm_config)?;
+                let (input_mint, output_mint, base_reserve, quote_reserve) = match trade_direction {
+                    TradeDirection::ZeroForOne => {
+                        pool_state.protocol_fees_token_0 =
+                            pool_state.protocol_fees_token_0.checked_add(protocol_fee)?;
+                        pool_state.fund_fees_token_0 =
+                            pool_state.fund_fees_token_0.checked_add(fund_fee)?;
+                        (
+                            pool_state.token_0_mint,
+                            pool_state.token_1_mint,
+                            input_reserve,
+                            output_reserve,
+                        )
+                    }
+                    TradeDirection::OneForZero => {
+                        pool_state.protocol_fees_token_1 =
+                            pool_state.protocol_fees_token_1.checked_add(protocol_fee)?;
+                        pool_state.fund_fees_token_1 =
+                            pool_state.fund_fees_token_1.checked_add(fund_fee)?;
+                        (
+                            pool_state.token_1_mint,
+                            pool_state.token_0_mint,
+                            output_reserve,
+                            input_reserve,
+                        )
+                    }
+                };
+                let pool_event = PoolEvent::Swap {
+                    context,
+                    input_mint: input_mint.to_string(),
+                    output_mint: output_mint.to_string(),
+                    amount_in: event.input_amount,
+                    amount_out: event.output_amount,
+                };
+                (pool_event, base_reserve, quote_reserve)
+            }
+            SegaEvent::LpChange(event) => {
+                let (base_reserve, quote_reserve) = event.reserves_after()?;
+                let pool_event = if event.is_deposit() {
+                    PoolEvent::Deposit {
+                        context,
+                        lp_amount: None,
+                        base_amount: event.token_0_amount,
+                        quote_amount: event.token_1_amount,
+                    }
+                } else {
+                    PoolEvent::Withdraw {
+                        context,
+                        lp_amount: None,
+                        base_amount: event.token_0_amount,
+                        quote_amount: event.token_1_amount,
+                    }
+                };
+                (pool_event, base_reserve, quote_reserve)
+            }
+        };
 
-use sega_cp_swap::{AmmConfig, CurveCalculator, PoolState, PoolStatusBitIndex};
+        // vault balances hold the reserves plus the accrued fees
+        for (key, reserve, protocol_fees, fund_fees) in [
+            (
+                "vault_0_amount",
+                base_reserve,
+                pool_state.protocol_fees_token_0,
+                pool_state.fund_fees_token_0,
+            ),
+            (
+                "vault_1_amount",
+                quote_reserve,
+                pool_state.protocol_fees_token_1,
+                pool_state.fund_fees_token_1,
+            ),
+        ] {
+            let vault_amount = reserve
+                .saturating_add(protocol_fees)
+                .saturating_add(fund_fees);
+            metadata.extra.insert(
+                key.to_string(),
+                PoolMetadataValue::Number(vault_amount as f64),
+            );
+        }
+        cache_reserves(&pool_address, Some((base_reserve, quote_reserve)));
+        metadata.base_reserve = Some(base_reserve as f64);
+        metadata.quote_reserve = Some(quote_reserve as f64);
+        Some(pool_event)
+    }
 
-pub struct SegaCPMM;
+    /// Quote burning `lp_token_amount` LP tokens of a pool previously loaded
+    /// with `fetch_pool_metadata`, net of Token-2022 transfer fees.
+    pub fn quote_withdraw(
+        &self,
+        lp_token_amount: u64,
+        metadata: &PoolMetadata,
+        slippage_bps: u64,
+    ) -> Option<WithdrawQuote> {
+        let pool_state = POOL_ADDRESS_MAP
+            .lock()
+            .unwrap()
+            .get(&metadata.pool_address)?
+            .clone();
+        let vault_0_amount =
+            get_extra!(metadata, "vault_0_amount", PoolMetadataValue::Number)? as u64;
+        let vault_1_amount =
+            get_extra!(metadata, "vault_1_amount", PoolMetadataValue::Number)? as u64;
+        let epoch = get_extra!(metadata, "epoch", PoolMetadataValue::Number)? as u64;
+        let token_0_transfer_fee = self.transfer_fee_for_mint(&metadata.base_mint, epoch);
+        let token_1_transfer_fee = self.transfer_fee_for_mint(&metadata.quote_mint, epoch);
+        LiquidityCalculator::withdraw(
+            &pool_state,
+            vault_0_amount,
+            vault_1_amount,
+            lp_token_amount,
+            token_0_transfer_fee.as_ref(),
+            token_1_transfer_fee.as_ref(),
+            slippage_bps,
+        )
+    }
 
-lazy_static::lazy_static! {
-    static ref TOKEN_MINT_MAP: Arc<Mutex<HashMap<String, StateWithExtensionsOwned<Mint>>>> = Arc::new(Mutex::new(HashMap::new()));
-    static ref POOL_ADDRESS_MAP: Arc<Mutex<HashMap<String, PoolState>>> = Arc::new(Mutex::new(HashMap::new()));
-}
+    /// Snapshot of the reserves, fee rates and transfer fees of a pool
+    /// previously loaded with `fetch_pool_metadata`.
+    pub fn pool_snapshot(&self, metadata: &PoolMetadata) -> Option<PoolSnapshot> {
+        let pool_state = POOL_ADDRESS_MAP
+            .lock()
+            .unwrap()
+            .get(&metadata.pool_address)?
+            .clone();
+        let amm_config = AmmConfig {
+            trade_fee_rate: get_extra!(metadata, "trade_fee_rate", PoolMetadataValue::Number)?
+                as u64,
+            protocol_fee_rate: get_extra!(metadata, "protocol_fee_rate", PoolMetadataValue::Number)?
+                as u64,
+            fund_fee_rate: get_extra!(metadata, "fund_fee_rate", PoolMetadataValue::Number)? as u64,
+            ..AmmConfig::default()
+        };
+        let vault_0_amount =
+            get_extra!(metadata, "vault_0_amount", PoolMetadataValue::Number)? as u64;
+        let vault_1_amount =
+            get_extra!(metadata, "vault_1_amount", PoolMetadataValue::Number)? as u64;
+        let epoch = get_extra!(metadata, "epoch", PoolMetadataValue::Number)? as u64;
+        PoolSnapshot::new(
+            &pool_state,
+            &amm_config,
+            vault_0_amount,
+            vault_1_amount,
+            self.transfer_fee_for_mint(&metadata.base_mint, epoch),
+            self.transfer_fee_for_mint(&metadata.quote_mint, epoch),
+        )
+        .ok()
+    }
 
-impl SegaCPMM {
-    fn find_pool_address_from_account(&self, account_address: &str) -> String {
-        let pool_address_map = POOL_ADDRESS_MAP.lock().unwrap();
-        let pool_state = pool_address_map.get(account_address);
-        if pool_state.is_some() {
-            account_address.to_string()
-        } else {
-            String::new()
+    /// Depth ladder of a pool: amount out and execution price for each of
+    /// `amounts_in`, `None` for sizes that can't be quoted.
+    /// `TradeDirection::ZeroForOne` sells the base mint.
+    pub fn quote_depth(
+        &self,
+        metadata: &PoolMetadata,
+        trade_direction: TradeDirection,
+        amounts_in: &[u64],
+    ) -> Vec<Option<DepthLevel>> {
+        match self.pool_snapshot(metadata) {
+            Some(snapshot) => DepthCalculator::ladder(&snapshot, trade_direction, amounts_in),
+            None => vec![None; amounts_in.len()],
         }
     }
 
-    fn is_valid_pool_address(&self, client: &RpcClient, pool_address: &str) -> bool {
-        let pool_state = self.derive_accounts_from_pool_address(client, pool_address);
-        pool_state.is_some()
+    /// Largest input into a pool whose price impact stays within
+    /// `slippage_bps`, fees included.
+    pub fn max_input_within_slippage(
+        &self,
+        metadata: &PoolMetadata,
+        trade_direction: TradeDirection,
+        slippage_bps: u64,
+    ) -> Option<DepthLevel> {
+        let snapshot = self.pool_snapshot(metadata)?;
+        DepthCalculator::max_input_within_slippage(&snapshot, trade_direction, slippage_bps)
+    }
+
+    /// UI amount scales of both mints of a pool previously loaded with
+    /// `fetch_pool_metadata`, at `unix_timestamp`
+    pub fn ui_scales(&self, metadata: &PoolMetadata, unix_timestamp: i64) -> Option<PoolUiScales> {
+        let token_mint_map = TOKEN_MINT_MAP.lock().unwrap();
+        Some(PoolUiScales {
+            token_0: UiAmountScale::from_mint(
+                token_mint_map.get(&metadata.base_mint)?,
+                unix_timestamp,
+            )?,
+            token_1: UiAmountScale::from_mint(
+                token_mint_map.get(&metadata.quote_mint)?,
+                unix_timestamp,
+            )?,
+        })
     }
 
-    fn derive_accounts_from_pool_address(
+    /// Quote swapping exactly `amount_in` raw units into a pool, with the
+    /// result in UI units at `unix_timestamp`
+    pub fn quote_ui(
+        &self,
+        metadata: &PoolMetadata,
+        trade_direction: TradeDirection,
+        amount_in: u64,
+        unix_timestamp: i64,
+    ) -> Option<UiSwapQuote> {
+        let quote = self
+            .pool_snapshot(metadata)?
+            .swap_base_input(trade_direction, amount_in)?;
+        self.ui_scales(metadata, unix_timestamp)?.quote(&quote)
+    }
+
+    /// `quote_depth` with every level in UI units at `unix_timestamp`
+    pub fn quote_depth_ui(
+        &self,
+        metadata: &PoolMetadata,
+        trade_direction: TradeDirection,
+        amounts_in: &[u64],
+        unix_timestamp: i64,
+    ) -> Vec<Option<UiDepthLevel>> {
+        let Some(ui_scales) = self.ui_scales(metadata, unix_timestamp) else {
+            return vec![None; amounts_in.len()];
+        };
+        self.quote_depth(metadata, trade_direction, amounts_in)
+            .iter()
+            .map(|level| ui_scales.depth_level(trade_direction, level.as_ref()?))
+            .collect()
+    }
+
+    /// Fetch and decode the oracle account of a pool
+    pub fn fetch_observation_state(
         &self,
         client: &RpcClient,
         pool_address: &str,
-    ) -> Option<PoolState> {
-        let pool_address = Pubkey::from_str(pool_address).unwrap();
-        if let Some(account) = client
-            .get_account_with_commitment(&pool_address, CommitmentConfig::processed())
-            .ok()?
+    ) -> anyhow::Result<ObservationState> {
+        let cached = POOL_ADDRESS_MAP.lock().unwrap().get(pool_address).cloned();
+        let pool_state = match cached {
+            Some(pool_state) => pool_state,
+            None => self
+                .derive_accounts_from_pool_address(client, pool_address)
+                .context("Pool account not found")?,
+        };
+        let account = client
+            .get_account_with_commitment(
+                &pool_state.observation_key,
+                CommitmentConfig::processed(),
+            )?
             .value
-        {
-            let mut data: &[u8] = &account.data;
-            let ret = PoolState::try_deserialize(&mut data).unwrap();
-            Some(ret)
-        } else {
-            None
-        }
+            .context("Observation account not found")?;
+        Ok(ObservationState::try_from_account_data(&account.data)?)
     }
 
-    fn extract_new_pool_address(&self, client: &RpcClient, tx_sig: &str) -> Option<String> {
-        let tx = client.get_transaction_with_config(
-            &Signature::from_str(tx_sig).ok()?,
-            RpcTransactionConfig {
-                encoding: Some(UiTransactionEncoding::Json),
-                commitment: Some(CommitmentConfig::confirmed()),
-                max_supported_transaction_version: Some(0),
-            },
-        ).ok()?;
-        match tx.transaction.transaction {
-            EncodedTransaction::Accounts(accounts) => {
-                accounts.account_keys.iter()
-                    .find(|key| key.writable && !key.signer)
-                    .map(|key| key.pubkey.clone())
-            }
-            _ => {
-                error!("Failed to extract new pool address from transaction: {}", tx_sig);
-                None
-            }
-        }
+    /// Time weighted average prices of a pool over the `window` seconds
+    /// ending at its most recent oracle observation.
+    pub fn fetch_twap(
+        &self,
+        client: &RpcClient,
+        pool_address: &str,
+        window: u64,
+    ) -> anyhow::Result<Twap> {
+        let observation_state = self.fetch_observation_state(client, pool_address)?;
+        Ok(observation_state.twap(window)?)
     }
-   
 }
 
 #[async_trait]
@@ -109,184 +1745,132 @@ impl Dex for SegaCPMM {
         }
         let is_trading =
             get_extra!(metadata, "is_trading", PoolMetadataValue::Bool).unwrap_or(false);
-        let epoch = Clock::get().unwrap().unix_timestamp;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
         let open_time =
-            get_extra!(metadata, "open_time", PoolMetadataValue::Number).unwrap_or(0.0) as i64;
-        if !is_trading || epoch < open_time {
+            get_extra!(metadata, "open_time", PoolMetadataValue::Number).unwrap_or(0.0) as u64;
+        if !is_trading || now < open_time {
             return 0.0;
         }
-        let token_0_transfer_fee = {
-            let token_mint_map = TOKEN_MINT_MAP.lock().unwrap();
-            let mint = token_mint_map.get(&metadata.base_mint).unwrap();
-            if let Some(transfer_fee_config) = mint.get_extension::<TransferFeeConfig>().ok() {
-                transfer_fee_config
-                    .calculate_epoch_fee(epoch as u64, amount_in as u64)
-                    .context("Fee 0 calculation failure")
-                    .unwrap_or(0)
-            } else {
-                0
-            }
-        };
-        let actual_amount_in = (amount_in as u64).saturating_sub(token_0_transfer_fee);
-        if actual_amount_in == 0 {
+        if self.is_excluded_as_manipulated(metadata) {
             return 0.0;
         }
-        let trade_fee_rate =
-            get_extra!(metadata, "trade_fee_rate", PoolMetadataValue::Number).unwrap_or(0.0) as u64;
-        let protocol_fee_rate = get_extra!(metadata, "protocol_fee_rate", PoolMetadataValue::Number)
-            .unwrap_or(0.0) as u64;
-        let fund_fee_rate =
-            get_extra!(metadata, "fund_fee_rate", PoolMetadataValue::Number).unwrap_or(0.0) as u64;
-        let total_token_0_amount = metadata.base_reserve.unwrap_or(0.0) as u128;
-        let total_token_1_amount = metadata.quote_reserve.unwrap_or(0.0) as u128;
-        let swap_result = CurveCalculator::swap_base_input(
-            u128::from(actual_amount_in),
-            total_token_0_amount,
-            total_token_1_amount,
-            trade_fee_rate,
-            protocol_fee_rate,
-            fund_fee_rate,
-        );
-        if swap_result.is_none() {
+        if self.is_excluded_by_extensions(metadata) {
             return 0.0;
         }
-        let swap_result = swap_result.unwrap();
-        let amount_out = swap_result.destination_amount_swapped as u64;
-        let token_1_transfer_fee = {
-            let token_mint_map = TOKEN_MINT_MAP.lock().unwrap();
-            let mint = token_mint_map.get(&metadata.quote_mint).unwrap();
-            if let Some(transfer_fee_config) = mint.get_extension::<TransferFeeConfig>().ok() {
-                transfer_fee_config
-                    .calculate_epoch_fee(epoch as u64, amount_out as u64)
-                    .context("Fee 1 calculation failure")
-                    .unwrap_or(0)
-            } else {
-                0
-            }
-        };
-        let actual_amount_out = amount_out.saturating_sub(token_1_transfer_fee);
-        actual_amount_out as f64
+        // the snapshot quote_depth and rank_pools_for_pair use, transfer
+        // fees at the epoch the metadata was loaded in
+        self.pool_snapshot(metadata)
+            .and_then(|snapshot| {
+                snapshot.swap_base_input(TradeDirection::ZeroForOne, amount_in as u64)
+            })
+            .map_or(0.0, |quote| quote.amount_received() as f64)
     }
 
     fn fetch_pool_addresses(&self, client: &RpcClient) -> Vec<String> {
-        let pool_len = sega_cp_swap::PoolState::LEN as u64;
-        let filters = Some(vec![RpcFilterType::DataSize(pool_len)]);
-        let account_config = RpcAccountInfoConfig {
-            encoding: Some(UiAccountEncoding::Base64Zstd),
-            ..RpcAccountInfoConfig::default()
-        };
-        let accounts = match client.get_program_accounts_with_config(
-            &self.dex_program_id().to_bytes().into(),
-            RpcProgramAccountsConfig {
-                filters,
-                account_config,
-                ..RpcProgramAccountsConfig::default()
-            },
-        ) {
-            Ok(accs) => accs,
-            Err(e) => {
+        self.fetch_pool_addresses_with_filters(client, Vec::new())
+            .unwrap_or_else(|e| {
                 error!("Failed to fetch {} pool addresses: {}", self.dex_name(), e);
-                return Vec::new();
-            }
-        };
-        accounts
-            .into_iter()
-            .map(|(pk, _acct)| pk.to_string())
-            .collect()
+                Vec::new()
+            })
     }
 
     async fn listen_new_pool_addresses(
         &self,
         client: &RpcClient,
-        address_tx: Sender<String>,
+        event_tx: Sender<PoolEvent>,
     ) -> Result<(), Box<dyn Error>> {
         let program_id = self.dex_program_id();
-        let ws_url = "wss://api.mainnet-beta.solana.com";
-        let (mut ws_stream, _) = connect_async(ws_url).await?;
         let subscribe_msg = format!(
-            r#"{{"jsonrpc":"2.0","id":1,"method":"logsSubscribe","params":["mentions","{}"]}}"#,
+            r#"{{"jsonrpc":"2.0","id":1,"method":"logsSubscribe","params":[{{"mentions":["{}"]}},{{"commitment":"confirmed"}}]}}"#,
             program_id
         );
-        ws_stream.send(Message::Text(subscribe_msg)).await?;
-
-        while let Some(msg) = ws_stream.next().await {
-            let msg = msg?;
-            if let Message::Text(text) = msg {
-                let log: serde_json::Value = serde_json::from_str(&text)?;
-                if log.get("result").is_some() {
-                    continue;
+        // activity after the newest transaction at startup is backfilled
+        // after every reconnect
+        let mut last_signature = self.newest_signature(client);
+        let mut backoff = self.backoff();
+        loop {
+            let Some(mut ws) = self.connect_ws(&mut backoff).await? else {
+                if event_tx.is_closed() {
+                    return Ok(());
                 }
-
-                let params = log.get("params").and_then(|p| p.get("result")).ok_or("No params")?;
-                let tx_sig = params.get("signature").and_then(|s| s.as_str()).ok_or("No signature")?;
-                let logs = params.get("logs").and_then(|l| l.as_array()).ok_or("No logs")?;
-
-                let tx = client.get_transaction_with_config(
-                    &Signature::from_str(tx_sig)?,
-                    RpcTransactionConfig {
-                        encoding: Some(UiTransactionEncoding::Json),
-                        commitment: Some(CommitmentConfig::confirmed()),
-                        max_supported_transaction_version: Some(0),
-                    },
-                )?;
-
-                if let Some(meta) = tx.transaction.meta {
-                    if meta.err.is_some() {
-                        continue;
+                continue;
+            };
+            // retry a failed lookup, so a later reconnect has somewhere to
+            // backfill from even if no notification arrives first
+            if last_signature.is_none() {
+                last_signature = self.newest_signature(client);
+            }
+            let result = async {
+                ws.send_text(subscribe_msg.clone()).await?;
+                // transactions confirmed since subscribing are both
+                // backfilled and notified
+                let mut backfilled = HashSet::new();
+                if let Some(until) = last_signature {
+                    for tx_sig in self.missed_signatures(client, &until) {
+                        self.process_signature(client, &tx_sig, &event_tx).await?;
+                        last_signature = Some(tx_sig);
+                        backfilled.insert(tx_sig);
                     }
                 }
 
-                let log_str = logs.iter().filter_map(|l| l.as_str()).collect::<Vec<&str>>().join(" ");
-                let account_keys = match &tx.transaction.transaction {
-                    EncodedTransaction::Json(transaction) => {
-                        match &transaction.message {
-                            UiMessage::Parsed(message) => {
-                                message.account_keys.iter()
-                                    .filter_map(|acc| Some(acc.pubkey.as_ref()))
-                                    .collect::<Vec<&str>>()
-                            },
-                            UiMessage::Raw(message) => {
-                                message.account_keys.iter()
-                                    .map(|key| key.as_str())
-                                    .collect::<Vec<&str>>()
-                            },
-                        }
-                    },
-                    _ => continue,
-                };
-
-                if let EncodedTransaction::Accounts(ref accounts) = tx.transaction.transaction {
-                    for (i, key) in account_keys.iter().enumerate() {
-                        if accounts.account_keys[i].writable {
-                            let pool_address = self.find_pool_address_from_account(&key.to_string());
-                            if pool_address.is_empty() {
-                                if let Some(pool_state) = self.derive_accounts_from_pool_address(client, &key.to_string()) {
-                                    POOL_ADDRESS_MAP.lock().unwrap().insert(key.to_string(), pool_state);
-                                    info!("Detected new {} pool address: {}", self.dex_name(), key);
-                                    address_tx.send(key.to_string()).await?;
-                                }
-                            } else {
-                                info!("Detected writable account affecting pool: {}, Pool: {}", tx_sig, key);
-                                address_tx.send(key.to_string()).await?;
-                            }
-                        }
+                loop {
+                    let Some(text) = ws.next_text().await? else {
+                        break;
+                    };
+                    backoff.reset();
+                    let log: serde_json::Value = serde_json::from_str(&text)?;
+                    if log.get("result").is_some() {
+                        continue;
                     }
-                }
 
-                if log_str.contains("initialize") {
-                    if let Some(pool_address) = self.extract_new_pool_address(client, tx_sig) {
-                        if self.is_valid_pool_address(client, &pool_address) {
-                            if let Some(accounts) = self.derive_accounts_from_pool_address(client, &pool_address) {
-                                POOL_ADDRESS_MAP.lock().unwrap().insert(pool_address.clone(), accounts);
-                                info!("Detected new {} pool address: {}", self.dex_name(), pool_address);
-                                address_tx.send(pool_address).await?;
+                    let params = log
+                        .get("params")
+                        .and_then(|p| p.get("result"))
+                        .ok_or("No params")?;
+                    let value = params.get("value").ok_or("No value")?;
+                    let tx_sig = value
+                        .get("signature")
+                        .and_then(|s| s.as_str())
+                        .ok_or("No signature")?;
+                    let tx_sig = Signature::from_str(tx_sig)?;
+                    if backfilled.remove(&tx_sig) {
+                        continue;
+                    }
+                    if value.get("err").is_some_and(|err| !err.is_null()) {
+                        last_signature = Some(tx_sig);
+                        continue;
+                    }
+                    // swaps and liquidity changes are applied from their
+                    // logged events, anything else needs the transaction
+                    let slot = params
+                        .get("context")
+                        .and_then(|c| c.get("slot"))
+                        .and_then(|s| s.as_u64())
+                        .unwrap_or_default();
+                    let logs: Vec<String> = value
+                        .get("logs")
+                        .and_then(|l| serde_json::from_value(l.clone()).ok())
+                        .unwrap_or_default();
+                    match self.log_events(&tx_sig, slot, &logs) {
+                        Some(events) => {
+                            for event in events {
+                                event_tx.send(event).await?;
                             }
                         }
+                        None => self.process_signature(client, &tx_sig, &event_tx).await?,
                     }
+                    last_signature = Some(tx_sig);
                 }
+                Ok::<(), Box<dyn Error>>(())
+            }
+            .await;
+            if event_tx.is_closed() {
+                return Ok(());
             }
+            tokio::time::sleep(self.reconnect_delay(&mut backoff, result)).await;
         }
-        Ok(())
     }
 
     fn fetch_pool_metadata(&self, client: &RpcClient, pool_address: &str) -> Option<PoolMetadata> {
@@ -302,29 +1886,12 @@ impl Dex for SegaCPMM {
         } else {
             None
         };
-        if pool_state.is_none() {
-            return None;
-        }
-        let pool_state = pool_state.unwrap();
+        let pool_state = pool_state?;
         POOL_ADDRESS_MAP
             .lock()
             .unwrap()
             .insert(pool_address.to_string(), pool_state.clone());
-        let amm_config: Option<AmmConfig> = if let Some(account) = client
-            .get_account_with_commitment(&pool_state.amm_config, CommitmentConfig::processed())
-            .ok()?
-            .value
-        {
-            let mut data: &[u8] = &account.data;
-            let ret = AmmConfig::try_deserialize(&mut data).unwrap();
-            Some(ret)
-        } else {
-            None
-        };
-        if amm_config.is_none() {
-            return None;
-        }
-        let amm_config = amm_config.unwrap();
+        let amm_config = self.amm_config(client, &pool_state.amm_config)?;
         let token_0_mint: Option<StateWithExtensionsOwned<Mint>> = if let Some(account) = client
             .get_account_with_commitment(&pool_state.token_0_mint, CommitmentConfig::processed())
             .ok()?
@@ -338,10 +1905,7 @@ impl Dex for SegaCPMM {
         } else {
             None
         };
-        if token_0_mint.is_none() {
-            return None;
-        }
-        let token_0_mint = token_0_mint.unwrap();
+        let token_0_mint = token_0_mint?;
         let token_1_mint: Option<StateWithExtensionsOwned<Mint>> = if let Some(account) = client
             .get_account_with_commitment(&pool_state.token_1_mint, CommitmentConfig::processed())
             .ok()?
@@ -355,10 +1919,7 @@ impl Dex for SegaCPMM {
         } else {
             None
         };
-        if token_1_mint.is_none() {
-            return None;
-        }
-        let token_1_mint = token_1_mint.unwrap();
+        let token_1_mint = token_1_mint?;
 
         TOKEN_MINT_MAP
             .lock()
@@ -382,10 +1943,7 @@ impl Dex for SegaCPMM {
         } else {
             None
         };
-        if token_0_vault.is_none() {
-            return None;
-        }
-        let token_0_vault = token_0_vault.unwrap();
+        let token_0_vault = token_0_vault?;
         let token_1_vault: Option<StateWithExtensionsOwned<Account>> = if let Some(account) = client
             .get_account_with_commitment(&pool_state.token_1_vault, CommitmentConfig::processed())
             .ok()?
@@ -399,10 +1957,24 @@ impl Dex for SegaCPMM {
         } else {
             None
         };
-        if token_1_vault.is_none() {
-            return None;
-        }
-        let token_1_vault = token_1_vault.unwrap();
+        let token_1_vault = token_1_vault?;
+
+        let extension_findings = {
+            let token_mint_map = TOKEN_MINT_MAP.lock().unwrap();
+            let mint_0 = token_mint_map.get(&pool_state.token_0_mint.to_string())?;
+            let mint_1 = token_mint_map.get(&pool_state.token_1_mint.to_string())?;
+            [
+                ("mint_0", classify_mint_extensions(mint_0)),
+                ("mint_1", classify_mint_extensions(mint_1)),
+                ("vault_0", classify_account_extensions(&token_0_vault)),
+                ("vault_1", classify_account_extensions(&token_1_vault)),
+            ]
+        };
+        let extension_risk = extension_findings
+            .iter()
+            .map(|(_, findings)| max_extension_risk(findings))
+            .max()
+            .unwrap_or_default();
 
         let vault_0_amount = if token_0_vault.base.is_frozen() {
             None
@@ -415,19 +1987,26 @@ impl Dex for SegaCPMM {
             Some(token_1_vault.base.amount)
         };
 
-        let base_reserve: Option<u64> = vault_0_amount
-            .context("Vault 0 missing or frozen")
-            .ok()?
-            .checked_sub(pool_state.protocol_fees_token_0 + pool_state.fund_fees_token_0);
-        let quote_reserve: Option<u64> = vault_1_amount
-            .context("Vault 1 missing or frozen")
-            .ok()?
-            .checked_sub(pool_state.protocol_fees_token_1 + pool_state.fund_fees_token_1);
+        let (base_reserve, quote_reserve) = match pool_state.checked_vault_amount_without_fee(
+            vault_0_amount.context("Vault 0 missing or frozen").ok()?,
+            vault_1_amount.context("Vault 1 missing or frozen").ok()?,
+        ) {
+            Ok((base_reserve, quote_reserve)) => (Some(base_reserve), Some(quote_reserve)),
+            Err(e) => {
+                error!(
+                    "{} pool {} is unquotable: {}",
+                    self.dex_name(),
+                    pool_address,
+                    e
+                );
+                (None, None)
+            }
+        };
 
         let mut extra = HashMap::new();
         extra.insert(
             "is_trading".to_string(),
-            PoolMetadataValue::Bool(pool_state.get_status_by_bit(PoolStatusBitIndex::Swap)),
+            PoolMetadataValue::Bool(pool_state.pool_status().can_swap()),
         );
         extra.insert(
             "open_time".to_string(),
@@ -445,7 +2024,85 @@ impl Dex for SegaCPMM {
             "fund_fee_rate".to_string(),
             PoolMetadataValue::Number(amm_config.fund_fee_rate as f64),
         );
-        Some(PoolMetadata {
+        extra.insert(
+            "lp_supply".to_string(),
+            PoolMetadataValue::Number(pool_state.lp_supply as f64),
+        );
+        extra.insert(
+            "vault_0_amount".to_string(),
+            PoolMetadataValue::Number(token_0_vault.base.amount as f64),
+        );
+        extra.insert(
+            "vault_1_amount".to_string(),
+            PoolMetadataValue::Number(token_1_vault.base.amount as f64),
+        );
+        extra.insert(
+            "extension_risk".to_string(),
+            PoolMetadataValue::String(extension_risk.to_string()),
+        );
+        extra.insert(
+            "extensions".to_string(),
+            PoolMetadataValue::Array(
+                extension_findings
+                    .iter()
+                    .flat_map(|(account, findings)| {
+                        findings.iter().map(move |finding| {
+                            PoolMetadataValue::Map(HashMap::from([
+                                (
+                                    "account".to_string(),
+                                    PoolMetadataValue::String(account.to_string()),
+                                ),
+                                (
+                                    "extension".to_string(),
+                                    PoolMetadataValue::String(format!("{:?}", finding.extension)),
+                                ),
+                                (
+                                    "risk".to_string(),
+                                    PoolMetadataValue::String(finding.risk.to_string()),
+                                ),
+                            ]))
+                        })
+                    })
+                    .collect(),
+            ),
+        );
+        if let Ok(epoch_info) = client.get_epoch_info() {
+            extra.insert(
+                "epoch".to_string(),
+                PoolMetadataValue::Number(epoch_info.epoch as f64),
+            );
+        }
+        if let (Some(max_twap_deviation_bps), Some(base_reserve), Some(quote_reserve)) = (
+            self.config.max_twap_deviation_bps,
+            base_reserve,
+            quote_reserve,
+        ) {
+            match self.fetch_twap(client, &pool_address.to_string(), self.config.twap_window) {
+                Ok(twap) => {
+                    if let Some(deviation_bps) =
+                        twap.spot_deviation_bps(base_reserve, quote_reserve)
+                    {
+                        extra.insert(
+                            "twap_deviation_bps".to_string(),
+                            PoolMetadataValue::Number(deviation_bps.to_f64().unwrap_or(f64::MAX)),
+                        );
+                        extra.insert(
+                            "twap_deviation_exceeded".to_string(),
+                            PoolMetadataValue::Bool(
+                                deviation_bps > Decimal::from(max_twap_deviation_bps),
+                            ),
+                        );
+                    }
+                }
+                Err(e) => info!(
+                    "No {} TWAP for pool {}: {}",
+                    self.dex_name(),
+                    pool_address,
+                    e
+                ),
+            }
+        }
+        let metadata = PoolMetadata {
             extra,
             base_reserve: base_reserve.map(|v| v as f64),
             quote_reserve: quote_reserve.map(|v| v as f64),
@@ -453,6 +2110,569 @@ impl Dex for SegaCPMM {
             base_mint: pool_state.token_0_mint.to_string(),
             quote_mint: pool_state.token_1_mint.to_string(),
             trade_fee: None,
+        };
+        cache_reserves(&pool_address.to_string(), base_reserve.zip(quote_reserve));
+        POOL_METADATA_MAP
+            .lock()
+            .unwrap()
+            .insert(pool_address.to_string(), metadata.clone());
+        Some(metadata)
+    }
+}
+
+// Keep the exact reserves of a pool next to its cached PoolState, removing
+// them while the pool is unquotable
+fn cache_reserves(pool_address: &str, reserves: Option<(u64, u64)>) {
+    let mut pool_reserve_map = POOL_RESERVE_MAP.lock().unwrap();
+    match reserves {
+        Some(reserves) => pool_reserve_map.insert(pool_address.to_string(), reserves),
+        None => pool_reserve_map.remove(pool_address),
+    };
+}
+
+// The creation of a pool, `None` while its PoolState is not initialized
+fn pool_created_event(pool_address: &str, slot: u64, pool_state: &PoolState) -> Option<PoolEvent> {
+    if pool_state.token_0_mint == Pubkey::default() || pool_state.token_1_mint == Pubkey::default()
+    {
+        return None;
+    }
+    Some(PoolEvent::Created {
+        context: PoolEventContext {
+            pool_address: pool_address.to_string(),
+            slot,
+            signature: None,
+        },
+        base_mint: pool_state.token_0_mint.to_string(),
+        quote_mint: pool_state.token_1_mint.to_string(),
+    })
+}
+
+// An account of a transaction and whether the transaction may write it
+#[derive(Clone, Copy)]
+struct TransactionAccount {
+    pubkey: Pubkey,
+    writable: bool,
+}
+
+// Accounts of a legacy or v0 transaction, in the order its account indexes
+// refer to: the static keys, then the writable and readonly addresses loaded
+// from lookup tables. Empty if any address doesn't parse, since the indexes
+// would no longer line up.
+fn transaction_accounts(tx: &EncodedTransactionWithStatusMeta) -> Vec<TransactionAccount> {
+    let parsed_accounts = |accounts: &[ParsedAccount]| {
+        accounts
+            .iter()
+            .map(|account| {
+                Some(TransactionAccount {
+                    pubkey: Pubkey::from_str(&account.pubkey).ok()?,
+                    writable: account.writable,
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+    };
+    let accounts = match &tx.transaction {
+        // parsed account lists already include the loaded addresses
+        EncodedTransaction::Json(UiTransaction {
+            message: UiMessage::Parsed(message),
+            ..
+        }) => parsed_accounts(&message.account_keys),
+        EncodedTransaction::Accounts(accounts) => parsed_accounts(&accounts.account_keys),
+        EncodedTransaction::Json(UiTransaction {
+            message: UiMessage::Raw(message),
+            ..
+        }) => message
+            .account_keys
+            .iter()
+            .map(|key| Pubkey::from_str(key).ok())
+            .collect::<Option<Vec<_>>>()
+            .and_then(|keys| with_loaded_addresses(&message.header, keys, tx.meta.as_ref())),
+        EncodedTransaction::LegacyBinary(_) | EncodedTransaction::Binary(..) => {
+            tx.transaction.decode().and_then(|transaction| {
+                with_loaded_addresses(
+                    transaction.message.header(),
+                    transaction.message.static_account_keys().to_vec(),
+                    tx.meta.as_ref(),
+                )
+            })
+        }
+    };
+    accounts.unwrap_or_default()
+}
+
+// Static keys with their writability per the message header, followed by
+// the addresses loaded from lookup tables
+fn with_loaded_addresses(
+    header: &MessageHeader,
+    static_keys: Vec<Pubkey>,
+    meta: Option<&UiTransactionStatusMeta>,
+) -> Option<Vec<TransactionAccount>> {
+    let num_static = static_keys.len();
+    let num_signed = usize::from(header.num_required_signatures);
+    let is_writable = |index: usize| {
+        if index < num_signed {
+            index < num_signed.saturating_sub(usize::from(header.num_readonly_signed_accounts))
+        } else {
+            index < num_static.saturating_sub(usize::from(header.num_readonly_unsigned_accounts))
+        }
+    };
+    let mut accounts: Vec<TransactionAccount> = static_keys
+        .into_iter()
+        .enumerate()
+        .map(|(index, pubkey)| TransactionAccount {
+            pubkey,
+            writable: is_writable(index),
+        })
+        .collect();
+    let loaded_addresses: Option<UiLoadedAddresses> =
+        meta.and_then(|meta| Option::from(meta.loaded_addresses.clone()));
+    if let Some(loaded_addresses) = loaded_addresses {
+        for (addresses, writable) in [
+            (&loaded_addresses.writable, true),
+            (&loaded_addresses.readonly, false),
+        ] {
+            for address in addresses {
+                accounts.push(TransactionAccount {
+                    pubkey: Pubkey::from_str(address).ok()?,
+                    writable,
+                });
+            }
+        }
+    }
+    Some(accounts)
+}
+
+// Balance change of every token account in a transaction, as
+// (account, mint, post - pre)
+fn token_balance_deltas(
+    meta: &UiTransactionStatusMeta,
+    account_keys: &[Pubkey],
+) -> Vec<(Pubkey, String, i128)> {
+    let balances = |balances: &OptionSerializer<Vec<UiTransactionTokenBalance>>| {
+        Option::<Vec<UiTransactionTokenBalance>>::from(balances.clone()).unwrap_or_default()
+    };
+    let mut deltas: HashMap<u8, (String, i128)> = HashMap::new();
+    for (sign, balances) in [
+        (-1, balances(&meta.pre_token_balances)),
+        (1, balances(&meta.post_token_balances)),
+    ] {
+        for balance in balances {
+            let amount: i128 = balance.ui_token_amount.amount.parse().unwrap_or(0);
+            deltas
+                .entry(balance.account_index)
+                .or_insert((balance.mint, 0))
+                .1 += sign * amount;
+        }
+    }
+    deltas
+        .into_iter()
+        .filter_map(|(account_index, (mint, delta))| {
+            Some((*account_keys.get(usize::from(account_index))?, mint, delta))
         })
+        .collect()
+}
+
+// An instruction with its program and accounts resolved to addresses
+struct RawInstruction {
+    program_id: Pubkey,
+    accounts: Vec<Pubkey>,
+    data: Vec<u8>,
+    stack_height: Option<u32>,
+}
+
+impl RawInstruction {
+    // None for indexes or addresses that don't resolve, and for instructions
+    // the node parsed, which are never the program's
+    fn resolve(instruction: &UiInstruction, account_keys: &[Pubkey]) -> Option<Self> {
+        match instruction {
+            UiInstruction::Compiled(instruction) => {
+                let key = |index: &u8| account_keys.get(usize::from(*index)).copied();
+                Some(Self {
+                    program_id: key(&instruction.program_id_index)?,
+                    accounts: instruction
+                        .accounts
+                        .iter()
+                        .map(key)
+                        .collect::<Option<_>>()?,
+                    data: bs58::decode(&instruction.data).into_vec().ok()?,
+                    stack_height: instruction.stack_height,
+                })
+            }
+            UiInstruction::Parsed(UiParsedInstruction::PartiallyDecoded(instruction)) => {
+                Some(Self {
+                    program_id: Pubkey::from_str(&instruction.program_id).ok()?,
+                    accounts: instruction
+                        .accounts
+                        .iter()
+                        .map(|key| Pubkey::from_str(key).ok())
+                        .collect::<Option<_>>()?,
+                    data: bs58::decode(&instruction.data).into_vec().ok()?,
+                    stack_height: instruction.stack_height,
+                })
+            }
+            UiInstruction::Parsed(UiParsedInstruction::Parsed(_)) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::Event;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde_json::json;
+    use spl_token_2022::solana_program::program_pack::Pack;
+    use spl_token_2022::state::AccountState;
+
+    fn amm_config() -> AmmConfig {
+        AmmConfig {
+            trade_fee_rate: 2_500,
+            protocol_fee_rate: 120_000,
+            fund_fee_rate: 40_000,
+            ..AmmConfig::default()
+        }
+    }
+
+    // Cache a pool of fresh mints and vaults, as fetch_pool_metadata does,
+    // with `reserves` as its cached reserves
+    fn cache_pool(reserves: (u64, u64)) -> (Pubkey, PoolState) {
+        let pool_address = Pubkey::new_unique();
+        let config_address = Pubkey::new_unique();
+        let pool_state = PoolState {
+            amm_config: config_address,
+            token_0_mint: Pubkey::new_unique(),
+            token_1_mint: Pubkey::new_unique(),
+            token_0_vault: Pubkey::new_unique(),
+            token_1_vault: Pubkey::new_unique(),
+            lp_mint: Pubkey::new_unique(),
+            ..PoolState::default()
+        };
+        AMM_CONFIG_MAP
+            .lock()
+            .unwrap()
+            .insert(config_address.to_string(), amm_config());
+        POOL_ADDRESS_MAP
+            .lock()
+            .unwrap()
+            .insert(pool_address.to_string(), pool_state.clone());
+        POOL_METADATA_MAP.lock().unwrap().insert(
+            pool_address.to_string(),
+            PoolMetadata {
+                pool_address: pool_address.to_string(),
+                base_mint: pool_state.token_0_mint.to_string(),
+                quote_mint: pool_state.token_1_mint.to_string(),
+                base_reserve: Some(reserves.0 as f64),
+                quote_reserve: Some(reserves.1 as f64),
+                trade_fee: None,
+                extra: HashMap::new(),
+            },
+        );
+        cache_reserves(&pool_address.to_string(), Some(reserves));
+        (pool_address, pool_state)
+    }
+
+    fn swap_event(pool_address: Pubkey, vaults_before: (u64, u64)) -> SwapEvent {
+        SwapEvent {
+            pool_id: pool_address,
+            input_vault_before: vaults_before.0,
+            output_vault_before: vaults_before.1,
+            input_amount: 10_000,
+            output_amount: 19_000,
+            input_transfer_fee: 0,
+            output_transfer_fee: 0,
+            base_input: true,
+        }
+    }
+
+    fn swap_logs(event: &SwapEvent) -> Vec<String> {
+        vec![
+            format!("Program {} invoke [1]", sega_cp_swap::ID),
+            "Program log: Instruction: SwapBaseInput".to_string(),
+            format!("Program data: {}", STANDARD.encode(event.data())),
+            format!("Program {} success", sega_cp_swap::ID),
+        ]
+    }
+
+    // A confirmed token_0 to token_1 swap as getTransaction returns it
+    fn swap_transaction(
+        pool_address: Pubkey,
+        pool_state: &PoolState,
+        event: &SwapEvent,
+    ) -> EncodedConfirmedTransactionWithStatusMeta {
+        let token_balance = |account_index: u8, mint: &Pubkey, amount: u64| {
+            json!({
+                "accountIndex": account_index,
+                "mint": mint.to_string(),
+                "uiTokenAmount": {
+                    "uiAmount": null,
+                    "decimals": 0,
+                    "amount": amount.to_string(),
+                    "uiAmountString": amount.to_string(),
+                },
+            })
+        };
+        serde_json::from_value(json!({
+            "slot": 1,
+            "blockTime": null,
+            "transaction": {
+                "signatures": [Signature::new_unique().to_string()],
+                "message": {
+                    "header": {
+                        "numRequiredSignatures": 1,
+                        "numReadonlySignedAccounts": 0,
+                        "numReadonlyUnsignedAccounts": 1,
+                    },
+                    "accountKeys": [
+                        Pubkey::new_unique().to_string(),
+                        pool_address.to_string(),
+                        pool_state.token_0_vault.to_string(),
+                        pool_state.token_1_vault.to_string(),
+                        sega_cp_swap::ID.to_string(),
+                    ],
+                    "recentBlockhash": Pubkey::default().to_string(),
+                    "instructions": [],
+                },
+            },
+            "meta": {
+                "err": null,
+                "status": { "Ok": null },
+                "fee": 5_000,
+                "preBalances": [],
+                "postBalances": [],
+                "logMessages": swap_logs(event),
+                "preTokenBalances": [
+                    token_balance(2, &pool_state.token_0_mint, event.input_vault_before),
+                    token_balance(3, &pool_state.token_1_mint, event.output_vault_before),
+                ],
+                "postTokenBalances": [
+                    token_balance(
+                        2,
+                        &pool_state.token_0_mint,
+                        event.input_vault_before + event.input_amount,
+                    ),
+                    token_balance(
+                        3,
+                        &pool_state.token_1_mint,
+                        event.output_vault_before - event.output_amount,
+                    ),
+                ],
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn fallback_swap_resyncs_the_cached_reserves() {
+        let sega = SegaCPMM::new(SegaCPMMConfig::default());
+        let client = RpcClient::new("http://127.0.0.1:8899".to_string());
+        // the cache missed an earlier swap
+        let (pool_address, pool_state) = cache_pool((990_000, 2_020_000));
+        let first = swap_event(pool_address, (1_000_000, 2_000_000));
+        let signature = Signature::new_unique();
+        assert_eq!(sega.log_events(&signature, 1, &swap_logs(&first)), None);
+
+        let events = sega.transaction_events(
+            &client,
+            &signature,
+            &swap_transaction(pool_address, &pool_state, &first),
+        );
+        assert!(matches!(
+            events.as_slice(),
+            [PoolEvent::Swap { input_mint, amount_in: 10_000, amount_out: 19_000, .. }]
+                if *input_mint == pool_state.token_0_mint.to_string()
+        ));
+        let reserves_after = first.reserves_after(&amm_config()).unwrap();
+        assert_eq!(
+            POOL_RESERVE_MAP
+                .lock()
+                .unwrap()
+                .get(&pool_address.to_string())
+                .copied(),
+            Some(reserves_after)
+        );
+
+        // the next swap is told by its logs alone again
+        let second = swap_event(pool_address, reserves_after);
+        let events = sega
+            .log_events(&Signature::new_unique(), 2, &swap_logs(&second))
+            .unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [PoolEvent::Swap { input_mint, .. }]
+                if *input_mint == pool_state.token_0_mint.to_string()
+        ));
+    }
+
+    fn vault_data(amount: u64) -> Vec<u8> {
+        let mut data = vec![0; Account::LEN];
+        Account::pack(
+            Account {
+                mint: Pubkey::new_unique(),
+                owner: Pubkey::new_unique(),
+                amount,
+                state: AccountState::Initialized,
+                ..Account::default()
+            },
+            &mut data,
+        )
+        .unwrap();
+        data
+    }
+
+    #[test]
+    fn account_updates_older_than_the_last_applied_are_dropped() {
+        let sega = SegaCPMM::new(SegaCPMMConfig::default());
+        let (pool_address, _) = cache_pool((0, 0));
+        let pool_address = pool_address.to_string();
+        sega.apply_account_update(&pool_address, PoolAccount::Vault0, 10, &vault_data(1_000));
+        assert_eq!(
+            sega.apply_account_update(&pool_address, PoolAccount::Vault1, 10, &vault_data(2_000)),
+            Some((Some(1_000), Some(2_000)))
+        );
+
+        // a resync snapshot read before the notifications above
+        assert_eq!(
+            sega.apply_account_update(&pool_address, PoolAccount::Vault0, 9, &vault_data(900)),
+            None
+        );
+        assert_eq!(
+            POOL_RESERVE_MAP.lock().unwrap().get(&pool_address).copied(),
+            Some((1_000, 2_000))
+        );
+
+        // a later write in the same slot still applies
+        assert_eq!(
+            sega.apply_account_update(&pool_address, PoolAccount::Vault0, 10, &vault_data(1_100)),
+            Some((Some(1_100), Some(2_000)))
+        );
+    }
+
+    #[test]
+    fn quote_matches_the_depth_ladder() {
+        let sega = SegaCPMM::new(SegaCPMMConfig::default());
+        let (pool_address, _) = cache_pool((1_000_000, 2_000_000));
+        let mut metadata = sega
+            .cached_pool_metadata(&pool_address.to_string())
+            .unwrap();
+        for (key, value) in [
+            ("is_trading", PoolMetadataValue::Bool(true)),
+            ("open_time", PoolMetadataValue::Number(0.0)),
+            ("trade_fee_rate", PoolMetadataValue::Number(2_500.0)),
+            ("protocol_fee_rate", PoolMetadataValue::Number(120_000.0)),
+            ("fund_fee_rate", PoolMetadataValue::Number(40_000.0)),
+            ("vault_0_amount", PoolMetadataValue::Number(1_000_000.0)),
+            ("vault_1_amount", PoolMetadataValue::Number(2_000_000.0)),
+            ("epoch", PoolMetadataValue::Number(700.0)),
+        ] {
+            metadata.extra.insert(key.to_string(), value);
+        }
+
+        let amounts_in = [1, 1_000, 250_000];
+        let ladder = sega.quote_depth(&metadata, TradeDirection::ZeroForOne, &amounts_in);
+        for (amount_in, level) in amounts_in.iter().zip(ladder) {
+            assert_eq!(
+                sega.quote(*amount_in as f64, &metadata),
+                level.map_or(0.0, |level| level.amount_out as f64)
+            );
+        }
+    }
+
+    // A raw JSON transaction with `static_keys` and, for a v0 message, the
+    // addresses it loaded from lookup tables
+    fn raw_transaction(
+        header: (u8, u8, u8),
+        static_keys: &[Pubkey],
+        loaded_addresses: Option<(&[Pubkey], &[Pubkey])>,
+    ) -> EncodedTransactionWithStatusMeta {
+        let to_strings =
+            |keys: &[Pubkey]| keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+        let mut meta = json!({
+            "err": null,
+            "status": { "Ok": null },
+            "fee": 5_000,
+            "preBalances": [],
+            "postBalances": [],
+        });
+        if let Some((writable, readonly)) = loaded_addresses {
+            meta["loadedAddresses"] = json!({
+                "writable": to_strings(writable),
+                "readonly": to_strings(readonly),
+            });
+        }
+        serde_json::from_value(json!({
+            "transaction": {
+                "signatures": [Signature::new_unique().to_string()],
+                "message": {
+                    "header": {
+                        "numRequiredSignatures": header.0,
+                        "numReadonlySignedAccounts": header.1,
+                        "numReadonlyUnsignedAccounts": header.2,
+                    },
+                    "accountKeys": to_strings(static_keys),
+                    "recentBlockhash": Pubkey::default().to_string(),
+                    "instructions": [],
+                },
+            },
+            "meta": meta,
+            "version": if loaded_addresses.is_some() { json!(0) } else { json!("legacy") },
+        }))
+        .unwrap()
+    }
+
+    fn accounts_of(tx: &EncodedTransactionWithStatusMeta) -> Vec<(Pubkey, bool)> {
+        transaction_accounts(tx)
+            .into_iter()
+            .map(|account| (account.pubkey, account.writable))
+            .collect()
+    }
+
+    #[test]
+    fn legacy_transaction_accounts_follow_the_header() {
+        let keys: Vec<Pubkey> = (0..6).map(|_| Pubkey::new_unique()).collect();
+        // two signers, the second readonly, and two readonly unsigned keys
+        let tx = raw_transaction((2, 1, 2), &keys, None);
+        assert_eq!(
+            accounts_of(&tx),
+            vec![
+                (keys[0], true),
+                (keys[1], false),
+                (keys[2], true),
+                (keys[3], true),
+                (keys[4], false),
+                (keys[5], false),
+            ]
+        );
+    }
+
+    #[test]
+    fn v0_transaction_accounts_append_the_loaded_addresses() {
+        let keys: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+        let writable: Vec<Pubkey> = (0..2).map(|_| Pubkey::new_unique()).collect();
+        let readonly: Vec<Pubkey> = (0..2).map(|_| Pubkey::new_unique()).collect();
+        let tx = raw_transaction((1, 0, 1), &keys, Some((&writable, &readonly)));
+        // the header only covers the static keys, so the last of them is
+        // readonly however many addresses were loaded after it
+        assert_eq!(
+            accounts_of(&tx),
+            vec![
+                (keys[0], true),
+                (keys[1], true),
+                (keys[2], true),
+                (keys[3], false),
+                (writable[0], true),
+                (writable[1], true),
+                (readonly[0], false),
+                (readonly[1], false),
+            ]
+        );
+
+        // an address that doesn't parse would shift every later index
+        let mut tx = tx;
+        if let Some(meta) = tx.meta.as_mut() {
+            meta.loaded_addresses = OptionSerializer::Some(UiLoadedAddresses {
+                writable: vec!["not an address".to_string()],
+                readonly: vec![],
+            });
+        }
+        assert!(accounts_of(&tx).is_empty());
     }
 }
@@ -0,0 +1,101 @@
+//! Websocket connection helpers shared by the listeners: reconnects with
+//! exponential backoff and a ping heartbeat that detects dead connections
+
+use futures_util::{sink::SinkExt, StreamExt};
+use std::{error::Error, time::Duration};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{protocol::Message, Error as WsError},
+    MaybeTlsStream, WebSocketStream,
+};
+
+// Exponential backoff between reconnect attempts
+pub(crate) struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    // The delay before the next attempt, doubling up to the maximum
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+// Whether connecting again can succeed: a malformed URL or a TLS
+// misconfiguration fails every attempt the same way
+pub(crate) fn is_retryable(e: &WsError) -> bool {
+    !matches!(
+        e,
+        WsError::Url(_) | WsError::Tls(_) | WsError::HttpFormat(_)
+    )
+}
+
+pub(crate) struct WsConnection {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    heartbeat: Duration,
+    awaiting_pong: bool,
+}
+
+impl WsConnection {
+    // Connect to `url` once
+    pub(crate) async fn connect(url: &str, heartbeat: Duration) -> Result<Self, WsError> {
+        let (stream, _) = connect_async(url).await?;
+        Ok(Self {
+            stream,
+            heartbeat,
+            awaiting_pong: false,
+        })
+    }
+
+    pub(crate) async fn send_text(&mut self, text: String) -> Result<(), Box<dyn Error>> {
+        self.stream.send(Message::Text(text)).await?;
+        Ok(())
+    }
+
+    // The next text message, answering pings and sending one whenever the
+    // connection is idle for a heartbeat. Returns `None` once the server
+    // closes the connection, and an error if a ping goes unanswered.
+    pub(crate) async fn next_text(&mut self) -> Result<Option<String>, Box<dyn Error>> {
+        loop {
+            let msg = match tokio::time::timeout(self.heartbeat, self.stream.next()).await {
+                Ok(msg) => msg,
+                Err(_) => {
+                    if self.awaiting_pong {
+                        return Err("websocket heartbeat timed out".into());
+                    }
+                    self.stream.send(Message::Ping(Vec::new())).await?;
+                    self.awaiting_pong = true;
+                    continue;
+                }
+            };
+            // any traffic proves the connection alive
+            self.awaiting_pong = false;
+            match msg {
+                None | Some(Ok(Message::Close(_))) => return Ok(None),
+                Some(Err(e)) => return Err(e.into()),
+                Some(Ok(Message::Text(text))) => return Ok(Some(text)),
+                Some(Ok(Message::Ping(payload))) => {
+                    self.stream.send(Message::Pong(payload)).await?;
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+}
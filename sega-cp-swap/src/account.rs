@@ -0,0 +1,78 @@
+//! Decoding of any account owned by the program
+
+use crate::amm_config::AmmConfig;
+use crate::pool::{ObservationState, PoolState};
+use anchor_lang::{AccountDeserialize, Discriminator};
+use std::fmt;
+
+/// Errors decoding raw account data
+#[derive(Clone, Debug, PartialEq)]
+pub enum AccountDecodeError {
+    /// The data is shorter than a discriminator
+    TooShort,
+    /// The discriminator matches none of the program's accounts
+    UnknownDiscriminator([u8; 8]),
+    /// The discriminator matched but the data doesn't deserialize
+    InvalidAccountData,
+}
+
+impl fmt::Display for AccountDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountDecodeError::TooShort => {
+                write!(f, "account data is shorter than a discriminator")
+            }
+            AccountDecodeError::UnknownDiscriminator(discriminator) => {
+                write!(f, "unknown account discriminator {:?}", discriminator)
+            }
+            AccountDecodeError::InvalidAccountData => write!(f, "invalid account data"),
+        }
+    }
+}
+
+impl std::error::Error for AccountDecodeError {}
+
+/// An account of the program, typed by its discriminator
+// decoded accounts are short-lived, boxing would only add allocations
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+pub enum SegaAccount {
+    PoolState(PoolState),
+    AmmConfig(AmmConfig),
+    ObservationState(ObservationState),
+}
+
+impl SegaAccount {
+    /// Decode raw account data, discriminator included
+    pub fn decode(data: &[u8]) -> Result<SegaAccount, AccountDecodeError> {
+        let discriminator = data.get(..8).ok_or(AccountDecodeError::TooShort)?;
+        if discriminator == PoolState::DISCRIMINATOR {
+            let mut data = data;
+            PoolState::try_deserialize(&mut data)
+                .map(SegaAccount::PoolState)
+                .map_err(|_| AccountDecodeError::InvalidAccountData)
+        } else if discriminator == AmmConfig::DISCRIMINATOR {
+            let mut data = data;
+            AmmConfig::try_deserialize(&mut data)
+                .map(SegaAccount::AmmConfig)
+                .map_err(|_| AccountDecodeError::InvalidAccountData)
+        } else if discriminator == ObservationState::DISCRIMINATOR {
+            ObservationState::try_from_account_data(data)
+                .map(SegaAccount::ObservationState)
+                .map_err(|_| AccountDecodeError::InvalidAccountData)
+        } else {
+            let mut unknown = [0u8; 8];
+            unknown.copy_from_slice(discriminator);
+            Err(AccountDecodeError::UnknownDiscriminator(unknown))
+        }
+    }
+
+    /// Name of the account type
+    pub fn name(&self) -> &'static str {
+        match self {
+            SegaAccount::PoolState(_) => "PoolState",
+            SegaAccount::AmmConfig(_) => "AmmConfig",
+            SegaAccount::ObservationState(_) => "ObservationState",
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use crate::constant_product::ConstantProductCurve;
-use crate::fees::Fees;
+use crate::fees::{Fees, FEE_RATE_DENOMINATOR_VALUE};
+use crate::math::U256;
+use std::cmp::Ordering;
 use std::fmt::Debug;
 
 /// The direction to round.  Used for pool token to trading token conversions to
@@ -13,6 +15,26 @@ pub enum RoundDirection {
     Ceiling,
 }
 
+/// The direction of a trade, token_0 to token_1 or the reverse.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TradeDirection {
+    /// Input token 0, output token 1
+    ZeroForOne,
+    /// Input token 1, output token 0
+    OneForZero,
+}
+
+impl TradeDirection {
+    /// Given a trade direction, gives the opposite direction of the trade, so
+    /// A to B becomes B to A, and vice versa
+    pub fn opposite(&self) -> TradeDirection {
+        match self {
+            TradeDirection::ZeroForOne => TradeDirection::OneForZero,
+            TradeDirection::OneForZero => TradeDirection::ZeroForOne,
+        }
+    }
+}
+
 /// Encodes results of depositing both sides at once
 #[derive(Debug, PartialEq)]
 pub struct TradingTokenResult {
@@ -41,6 +63,15 @@ pub struct SwapResult {
     pub fund_fee: u128,
 }
 
+/// Encodes the swap that moves a pool's price to a target price
+#[derive(Debug, PartialEq)]
+pub struct TargetPriceSwap {
+    /// Direction of the swap
+    pub trade_direction: TradeDirection,
+    /// Results of swapping `swap_result.source_amount_swapped` in
+    pub swap_result: SwapResult,
+}
+
 /// Concrete struct to wrap around the trait object which performs calculation.
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct CurveCalculator {}
@@ -130,4 +161,247 @@ impl CurveCalculator {
             round_direction,
         )
     }
+
+    /// Find the smallest input amount, and its direction, that moves the
+    /// pool's marginal price (token_1 per token_0, reserves net of the
+    /// protocol and fund fees) to `target_price_numerator /
+    /// target_price_denominator` or past it. Returns `None` if the pool is
+    /// already at the target or it can't be reached with a u64 input.
+    pub fn swap_to_target_price(
+        swap_token_0_amount: u128,
+        swap_token_1_amount: u128,
+        target_price_numerator: u128,
+        target_price_denominator: u128,
+        trade_fee_rate: u64,
+        protocol_fee_rate: u64,
+        fund_fee_rate: u64,
+    ) -> Option<TargetPriceSwap> {
+        if swap_token_0_amount == 0
+            || swap_token_1_amount == 0
+            || target_price_numerator == 0
+            || target_price_denominator == 0
+        {
+            return None;
+        }
+        // Buying token_0 with token_1 raises the price, selling lowers it
+        let trade_direction = match (U256::from(swap_token_1_amount)
+            * U256::from(target_price_denominator))
+        .cmp(&(U256::from(target_price_numerator) * U256::from(swap_token_0_amount)))
+        {
+            Ordering::Less => TradeDirection::OneForZero,
+            Ordering::Greater => TradeDirection::ZeroForOne,
+            Ordering::Equal => return None,
+        };
+        let (swap_source_amount, swap_destination_amount) = match trade_direction {
+            TradeDirection::ZeroForOne => (swap_token_0_amount, swap_token_1_amount),
+            TradeDirection::OneForZero => (swap_token_1_amount, swap_token_0_amount),
+        };
+        let swap = |source_amount: u128| {
+            Self::swap_base_input(
+                source_amount,
+                swap_source_amount,
+                swap_destination_amount,
+                trade_fee_rate,
+                protocol_fee_rate,
+                fund_fee_rate,
+            )
+        };
+        let reached = |source_amount: u128| -> bool {
+            let Some(result) = swap(source_amount) else {
+                return false;
+            };
+            let Some(new_source_amount) = result
+                .new_swap_source_amount
+                .checked_sub(result.protocol_fee + result.fund_fee)
+            else {
+                return false;
+            };
+            let (new_token_0_amount, new_token_1_amount) = match trade_direction {
+                TradeDirection::ZeroForOne => {
+                    (new_source_amount, result.new_swap_destination_amount)
+                }
+                TradeDirection::OneForZero => {
+                    (result.new_swap_destination_amount, new_source_amount)
+                }
+            };
+            let price = U256::from(new_token_1_amount) * U256::from(target_price_denominator);
+            let target = U256::from(target_price_numerator) * U256::from(new_token_0_amount);
+            match trade_direction {
+                TradeDirection::ZeroForOne => price <= target,
+                TradeDirection::OneForZero => price >= target,
+            }
+        };
+
+        // Closed form, treating fees as continuous: with a = 1 - trade fee
+        // and b = 1 - trade fee * (protocol + fund) share, the input d solves
+        // (s + a * d) * (s + b * d) = k, where s is the source reserve and k
+        // the source reserve squared at the target price.
+        let fee_rate = trade_fee_rate as f64 / FEE_RATE_DENOMINATOR_VALUE as f64;
+        let removed_share =
+            (protocol_fee_rate + fund_fee_rate) as f64 / FEE_RATE_DENOMINATOR_VALUE as f64;
+        let a = 1.0 - fee_rate;
+        let b = 1.0 - fee_rate * removed_share;
+        let s = swap_source_amount as f64;
+        let target_price = target_price_numerator as f64 / target_price_denominator as f64;
+        let k = match trade_direction {
+            TradeDirection::ZeroForOne => s * swap_destination_amount as f64 / target_price,
+            TradeDirection::OneForZero => s * swap_destination_amount as f64 * target_price,
+        };
+        let discriminant = s * s * (a + b) * (a + b) - 4.0 * a * b * (s * s - k);
+        let estimate = (-s * (a + b) + discriminant.max(0.0).sqrt()) / (2.0 * a * b);
+
+        // Integer rounding moves the exact answer a little off the estimate:
+        // bracket it by galloping away from the estimate, then bisect.
+        let max_amount = u128::from(u64::MAX);
+        let estimate = (estimate.max(1.0) as u128).min(max_amount);
+        let (mut low, mut high) = if reached(estimate) {
+            let mut step = 1;
+            let mut low = estimate.saturating_sub(step);
+            while low > 0 && reached(low) {
+                step *= 2;
+                low = estimate.saturating_sub(step);
+            }
+            (low, estimate)
+        } else {
+            let mut step = 1;
+            let mut high = estimate.saturating_add(step).min(max_amount);
+            while !reached(high) {
+                if high == max_amount {
+                    return None;
+                }
+                step *= 2;
+                high = estimate.saturating_add(step).min(max_amount);
+            }
+            (estimate, high)
+        };
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if reached(mid) {
+                high = mid;
+            } else {
+                low = mid;
+            }
+        }
+        Some(TargetPriceSwap {
+            trade_direction,
+            swap_result: swap(high)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN_0_AMOUNT: u128 = 1_000_000_007;
+    const TOKEN_1_AMOUNT: u128 = 3_000_000_019;
+    // trade fee, protocol fee and fund fee rates
+    const FEE_RATES: [(u64, u64, u64); 3] = [(0, 0, 0), (2_500, 120_000, 40_000), (10_000, 0, 0)];
+
+    // token_1 per token_0 after swapping `amount_in`, reserves net of the
+    // protocol and fund fees, as (numerator, denominator)
+    fn price_after(
+        trade_direction: TradeDirection,
+        amount_in: u128,
+        (trade_fee_rate, protocol_fee_rate, fund_fee_rate): (u64, u64, u64),
+    ) -> (u128, u128) {
+        let (source_amount, destination_amount) = match trade_direction {
+            TradeDirection::ZeroForOne => (TOKEN_0_AMOUNT, TOKEN_1_AMOUNT),
+            TradeDirection::OneForZero => (TOKEN_1_AMOUNT, TOKEN_0_AMOUNT),
+        };
+        let result = CurveCalculator::swap_base_input(
+            amount_in,
+            source_amount,
+            destination_amount,
+            trade_fee_rate,
+            protocol_fee_rate,
+            fund_fee_rate,
+        )
+        .unwrap();
+        let source_amount = result.new_swap_source_amount - result.protocol_fee - result.fund_fee;
+        match trade_direction {
+            TradeDirection::ZeroForOne => (result.new_swap_destination_amount, source_amount),
+            TradeDirection::OneForZero => (source_amount, result.new_swap_destination_amount),
+        }
+    }
+
+    fn is_reached(
+        trade_direction: TradeDirection,
+        (numerator, denominator): (u128, u128),
+        (target_numerator, target_denominator): (u128, u128),
+    ) -> bool {
+        let price = U256::from(numerator) * U256::from(target_denominator);
+        let target = U256::from(target_numerator) * U256::from(denominator);
+        match trade_direction {
+            TradeDirection::ZeroForOne => price <= target,
+            TradeDirection::OneForZero => price >= target,
+        }
+    }
+
+    fn swap_to_target_price(
+        target: (u128, u128),
+        (trade_fee_rate, protocol_fee_rate, fund_fee_rate): (u64, u64, u64),
+    ) -> Option<TargetPriceSwap> {
+        CurveCalculator::swap_to_target_price(
+            TOKEN_0_AMOUNT,
+            TOKEN_1_AMOUNT,
+            target.0,
+            target.1,
+            trade_fee_rate,
+            protocol_fee_rate,
+            fund_fee_rate,
+        )
+    }
+
+    fn assert_smallest_input_reaching(target: (u128, u128), trade_direction: TradeDirection) {
+        for fee_rates in FEE_RATES {
+            let swap = swap_to_target_price(target, fee_rates).unwrap();
+            assert_eq!(swap.trade_direction, trade_direction);
+            let amount_in = swap.swap_result.source_amount_swapped;
+            assert!(is_reached(
+                trade_direction,
+                price_after(trade_direction, amount_in, fee_rates),
+                target
+            ));
+            assert!(!is_reached(
+                trade_direction,
+                price_after(trade_direction, amount_in - 1, fee_rates),
+                target
+            ));
+        }
+    }
+
+    #[test]
+    fn selling_token_0_lowers_the_price_to_the_target() {
+        assert_smallest_input_reaching((5, 2), TradeDirection::ZeroForOne);
+        // a move of well under a bp
+        assert_smallest_input_reaching((2_999_999, 1_000_000), TradeDirection::ZeroForOne);
+    }
+
+    #[test]
+    fn buying_token_0_raises_the_price_to_the_target() {
+        assert_smallest_input_reaching((7, 2), TradeDirection::OneForZero);
+        assert_smallest_input_reaching((3_000_001, 1_000_000), TradeDirection::OneForZero);
+    }
+
+    #[test]
+    fn unreachable_targets_return_none() {
+        for fee_rates in FEE_RATES {
+            // the pool is already at the target price
+            assert_eq!(
+                swap_to_target_price((TOKEN_1_AMOUNT, TOKEN_0_AMOUNT), fee_rates),
+                None
+            );
+            // further than any u64 input moves the price
+            assert_eq!(swap_to_target_price((1, u128::MAX), fee_rates), None);
+            assert_eq!(swap_to_target_price((u128::MAX, 1), fee_rates), None);
+            // no price
+            assert_eq!(swap_to_target_price((0, 1), fee_rates), None);
+            assert_eq!(swap_to_target_price((1, 0), fee_rates), None);
+        }
+        assert_eq!(
+            CurveCalculator::swap_to_target_price(0, TOKEN_1_AMOUNT, 3, 1, 2_500, 0, 0),
+            None
+        );
+    }
 }
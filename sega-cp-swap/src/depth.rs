@@ -0,0 +1,270 @@
+//! Liquidity depth of a pool, quoted exactly like a swap
+
+use crate::calculator::TradeDirection;
+use crate::price::PriceCalculator;
+use crate::quote::{PoolSnapshot, SwapQuote};
+use rust_decimal::Decimal;
+
+/// Input sizes up to which price impact is dominated by rounding
+const ROUNDING_SCAN_AMOUNT: u64 = 1_000;
+
+/// One level of a depth ladder
+#[derive(Clone, Debug, PartialEq)]
+pub struct DepthLevel {
+    /// Amount the user transfers in
+    pub amount_in: u64,
+    /// Amount of output token the user receives
+    pub amount_out: u64,
+    /// Price of token_0 in token_1 the user gets, fees included
+    pub execution_price: Decimal,
+    /// Distance of `execution_price` from the spot price, in bps
+    pub price_impact_bps: Decimal,
+}
+
+/// Builds depth ladders over a pool snapshot with swap_base_input quotes,
+/// so every level matches what the program would pay out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DepthCalculator {}
+
+impl DepthCalculator {
+    /// Quote a level for `amount_in`. Trade and transfer fees count towards
+    /// the price impact.
+    pub fn level(
+        snapshot: &PoolSnapshot,
+        trade_direction: TradeDirection,
+        amount_in: u64,
+    ) -> Option<DepthLevel> {
+        let quote = snapshot.swap_base_input(trade_direction, amount_in)?;
+        Self::level_from_quote(snapshot, &quote)
+    }
+
+    /// Quote a level for each of `amounts_in`, `None` for sizes the pool
+    /// can't fill.
+    pub fn ladder(
+        snapshot: &PoolSnapshot,
+        trade_direction: TradeDirection,
+        amounts_in: &[u64],
+    ) -> Vec<Option<DepthLevel>> {
+        amounts_in
+            .iter()
+            .map(|amount_in| Self::level(snapshot, trade_direction, *amount_in))
+            .collect()
+    }
+
+    /// Find the largest input whose price impact stays within
+    /// `slippage_bps`. Returns `None` if every swap exceeds it, e.g. when
+    /// the fees alone are larger.
+    pub fn max_input_within_slippage(
+        snapshot: &PoolSnapshot,
+        trade_direction: TradeDirection,
+        slippage_bps: u64,
+    ) -> Option<DepthLevel> {
+        let slippage_bps = Decimal::from(slippage_bps);
+        let within = |amount_in: u64| {
+            Self::level(snapshot, trade_direction, amount_in)
+                .filter(|level| level.price_impact_bps <= slippage_bps)
+        };
+        let (input_amount, _) = snapshot.reserves(trade_direction);
+        let smallest = Self::min_input_within_slippage(snapshot, trade_direction, slippage_bps)?;
+        // Past the smallest size within slippage the curve's impact only
+        // grows, so bisect upward from it
+        let (mut low, mut high) = (smallest, input_amount.max(smallest));
+        while low < high {
+            let mid = low + (high - low + 1) / 2;
+            if within(mid).is_some() {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        within(low)
+    }
+
+    // The smallest input whose price impact stays within `slippage_bps`.
+    // Impact is not monotonic in size: the first units are dominated by
+    // rounding, then it falls while fixed costs such as a capped transfer
+    // fee spread over more input, and only then grows with the curve.
+    fn min_input_within_slippage(
+        snapshot: &PoolSnapshot,
+        trade_direction: TradeDirection,
+        slippage_bps: Decimal,
+    ) -> Option<u64> {
+        let impact = |amount_in: u64| {
+            Self::level(snapshot, trade_direction, amount_in)
+                .map_or(Decimal::MAX, |level| level.price_impact_bps)
+        };
+        let (input_amount, _) = snapshot.reserves(trade_direction);
+        // Rounding-dominated sizes are tried one by one
+        let scan_end = input_amount.min(ROUNDING_SCAN_AMOUNT);
+        if let Some(amount_in) = (1..=scan_end).find(|amount_in| impact(*amount_in) <= slippage_bps)
+        {
+            return Some(amount_in);
+        }
+        if scan_end == input_amount {
+            return None;
+        }
+        // Beyond them impact falls then rises, so ternary search for the
+        // cheapest size. Sizes too small to quote sit on the falling side.
+        let (mut low, mut high) = (scan_end + 1, input_amount);
+        while high - low > 2 {
+            let third = (high - low) / 3;
+            let (impact_low, impact_high) = (impact(low + third), impact(high - third));
+            if impact_low <= impact_high && impact_low != Decimal::MAX {
+                high -= third;
+            } else {
+                low += third;
+            }
+        }
+        let cheapest = (low..=high).min_by_key(|amount_in| impact(*amount_in))?;
+        if impact(cheapest) > slippage_bps {
+            return None;
+        }
+        // Impact falls up to the cheapest size, so bisect for the first
+        // size within slippage
+        let (mut low, mut high) = (scan_end + 1, cheapest);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if impact(mid) <= slippage_bps {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        Some(low)
+    }
+
+    fn level_from_quote(snapshot: &PoolSnapshot, quote: &SwapQuote) -> Option<DepthLevel> {
+        let amount_out = quote.amount_received();
+        let (token_0_amount, token_1_amount) = match quote.trade_direction {
+            TradeDirection::ZeroForOne => (quote.amount_in, amount_out),
+            TradeDirection::OneForZero => (amount_out, quote.amount_in),
+        };
+        let execution_price = PriceCalculator::price(
+            u128::from(token_0_amount),
+            u128::from(token_1_amount),
+            snapshot.mint_0_decimals,
+            snapshot.mint_1_decimals,
+        )?;
+        Some(DepthLevel {
+            amount_in: quote.amount_in,
+            amount_out,
+            price_impact_bps: PriceCalculator::price_impact_bps(
+                snapshot.spot_price()?,
+                execution_price,
+            )?,
+            execution_price,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spl_token_2022::extension::transfer_fee::TransferFee;
+
+    fn snapshot(
+        token_0_amount: u64,
+        token_1_amount: u64,
+        trade_fee_rate: u64,
+        token_0_transfer_fee: Option<TransferFee>,
+    ) -> PoolSnapshot {
+        PoolSnapshot {
+            token_0_amount,
+            token_1_amount,
+            lp_supply: token_0_amount,
+            trade_fee_rate,
+            token_0_transfer_fee,
+            mint_0_decimals: 6,
+            mint_1_decimals: 6,
+            ..PoolSnapshot::default()
+        }
+    }
+
+    fn within(snapshot: &PoolSnapshot, amount_in: u64, slippage_bps: u64) -> bool {
+        DepthCalculator::level(snapshot, TradeDirection::ZeroForOne, amount_in)
+            .is_some_and(|level| level.price_impact_bps <= Decimal::from(slippage_bps))
+    }
+
+    #[test]
+    fn max_input_within_slippage_scans_past_rounding() {
+        // small reserves, so the first sizes are dominated by rounding
+        let snapshot = snapshot(20_000, 60_007, 2_500, None);
+        for slippage_bps in [160, 200, 500] {
+            let smallest = (1..=snapshot.token_0_amount)
+                .find(|amount_in| within(&snapshot, *amount_in, slippage_bps))
+                .unwrap();
+            assert!(!within(&snapshot, 1, slippage_bps));
+            assert_eq!(
+                DepthCalculator::min_input_within_slippage(
+                    &snapshot,
+                    TradeDirection::ZeroForOne,
+                    Decimal::from(slippage_bps)
+                ),
+                Some(smallest)
+            );
+            let level = DepthCalculator::max_input_within_slippage(
+                &snapshot,
+                TradeDirection::ZeroForOne,
+                slippage_bps,
+            )
+            .unwrap();
+            assert!(level.amount_in >= smallest);
+            assert!(within(&snapshot, level.amount_in, slippage_bps));
+        }
+    }
+
+    #[test]
+    fn max_input_within_slippage_finds_a_narrow_window() {
+        // a 1% transfer fee capped at 476 units costs less and less past
+        // 47_600 units while the curve's impact grows, bottoming out at
+        // 13.8 bps around 690_000 units: only sizes from about 580_000 to
+        // 820_000 stay within 14 bps, between two halving steps
+        let transfer_fee = TransferFee {
+            epoch: 0.into(),
+            maximum_fee: 476.into(),
+            transfer_fee_basis_points: 100.into(),
+        };
+        let snapshot = snapshot(1_000_000_000, 1_000_000_000, 0, Some(transfer_fee));
+        let level =
+            DepthCalculator::max_input_within_slippage(&snapshot, TradeDirection::ZeroForOne, 14)
+                .expect("an input within slippage exists");
+        assert!(within(&snapshot, level.amount_in, 14));
+        assert!(!within(&snapshot, level.amount_in + 1, 14));
+        assert!(!within(&snapshot, 100_000, 14));
+        // the sizes a bisection from the reserve would try
+        assert!(!within(&snapshot, 976_562, 14));
+        assert!(!within(&snapshot, 488_281, 14));
+    }
+
+    #[test]
+    fn max_input_within_slippage_is_none_when_fees_alone_exceed_it() {
+        // a 1% trade fee is 100 bps of impact on any size
+        let snapshot = snapshot(1_000_000, 1_000_000, 10_000, None);
+        assert_eq!(
+            DepthCalculator::max_input_within_slippage(&snapshot, TradeDirection::ZeroForOne, 50),
+            None
+        );
+    }
+
+    #[test]
+    fn ladder_keeps_a_level_per_size() {
+        let snapshot = snapshot(1_000_000, 1_000, 2_500, None);
+        let ladder = DepthCalculator::ladder(
+            &snapshot,
+            TradeDirection::ZeroForOne,
+            &[1, 100_000, 0, 500_000],
+        );
+        assert_eq!(ladder.len(), 4);
+        // one unit in rounds to nothing out, and zero is no swap
+        assert_eq!(ladder[0], None);
+        assert_eq!(ladder[2], None);
+        assert_eq!(
+            ladder[1].as_ref().map(|level| level.amount_in),
+            Some(100_000)
+        );
+        assert_eq!(
+            ladder[3].as_ref().map(|level| level.amount_in),
+            Some(500_000)
+        );
+    }
+}
@@ -0,0 +1,490 @@
+//! Off-chain emulation of the program's state transitions
+
+use crate::amm_config::AmmConfig;
+use crate::calculator::TradeDirection;
+use crate::liquidity::{DepositQuote, LiquidityCalculator, WithdrawQuote};
+use crate::pool::{ObservationState, PoolState, PoolStateError};
+use crate::quote::{PoolSnapshot, SwapQuote};
+use spl_token_2022::extension::transfer_fee::TransferFeeConfig;
+use std::fmt;
+
+/// Errors the program would fail an instruction with
+#[derive(Clone, Debug, PartialEq)]
+pub enum EmulatorError {
+    /// The pool's status or open time doesn't allow the operation
+    NotApproved,
+    /// The input amounts or lp amount are zero or out of range
+    InvalidInput,
+    /// The user's minimum or maximum amount was not met
+    ExceededSlippage,
+    /// The operation would move zero trading tokens
+    ZeroTradingTokens,
+    /// The vault balances don't cover the pool's accrued fees
+    PoolState(PoolStateError),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::NotApproved => write!(f, "operation not approved for this pool"),
+            EmulatorError::InvalidInput => write!(f, "invalid input"),
+            EmulatorError::ExceededSlippage => write!(f, "exceeds desired slippage limit"),
+            EmulatorError::ZeroTradingTokens => {
+                write!(f, "given pool token amount results in zero trading tokens")
+            }
+            EmulatorError::PoolState(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
+impl From<PoolStateError> for EmulatorError {
+    fn from(e: PoolStateError) -> Self {
+        EmulatorError::PoolState(e)
+    }
+}
+
+/// Owns a pool's accounts and applies instructions to them as the program
+/// would, so sequences of swaps and liquidity changes can be simulated
+/// without a validator.
+#[derive(Clone)]
+pub struct PoolEmulator {
+    pub pool_state: PoolState,
+    pub amm_config: AmmConfig,
+    pub observation_state: ObservationState,
+    /// Raw token balance of vault 0, accrued fees included
+    pub vault_0_amount: u64,
+    /// Raw token balance of vault 1, accrued fees included
+    pub vault_1_amount: u64,
+    /// TransferFeeConfig of mint 0, `None` if it has none
+    pub token_0_transfer_fee_config: Option<TransferFeeConfig>,
+    /// TransferFeeConfig of mint 1, `None` if it has none
+    pub token_1_transfer_fee_config: Option<TransferFeeConfig>,
+    /// Epoch the next instruction executes in
+    pub epoch: u64,
+    /// Unix timestamp the next instruction executes at
+    pub block_timestamp: u64,
+}
+
+impl PoolEmulator {
+    pub fn new(
+        pool_state: PoolState,
+        amm_config: AmmConfig,
+        vault_0_amount: u64,
+        vault_1_amount: u64,
+        token_0_transfer_fee_config: Option<TransferFeeConfig>,
+        token_1_transfer_fee_config: Option<TransferFeeConfig>,
+    ) -> Self {
+        let observation_state = ObservationState::new(pool_state.observation_key);
+        Self {
+            epoch: pool_state.recent_epoch,
+            block_timestamp: pool_state.open_time,
+            pool_state,
+            amm_config,
+            observation_state,
+            vault_0_amount,
+            vault_1_amount,
+            token_0_transfer_fee_config,
+            token_1_transfer_fee_config,
+        }
+    }
+
+    /// Move the clock the following instructions execute at
+    pub fn set_clock(&mut self, epoch: u64, block_timestamp: u64) {
+        self.epoch = epoch;
+        self.block_timestamp = block_timestamp;
+    }
+
+    /// The pool as seen by a quote at the current epoch
+    pub fn snapshot(&self) -> Result<PoolSnapshot, EmulatorError> {
+        Ok(PoolSnapshot::new(
+            &self.pool_state,
+            &self.amm_config,
+            self.vault_0_amount,
+            self.vault_1_amount,
+            self.token_0_transfer_fee_config
+                .map(|config| *config.get_epoch_fee(self.epoch)),
+            self.token_1_transfer_fee_config
+                .map(|config| *config.get_epoch_fee(self.epoch)),
+        )?)
+    }
+
+    /// Apply swap_base_input
+    pub fn swap_base_input(
+        &mut self,
+        trade_direction: TradeDirection,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<SwapQuote, EmulatorError> {
+        self.check_swap_approved()?;
+        let quote = self
+            .snapshot()?
+            .swap_base_input(trade_direction, amount_in)
+            .ok_or(EmulatorError::ZeroTradingTokens)?;
+        if quote.amount_received() < minimum_amount_out {
+            return Err(EmulatorError::ExceededSlippage);
+        }
+        self.apply_swap(&quote)?;
+        Ok(quote)
+    }
+
+    /// Apply swap_base_output
+    pub fn swap_base_output(
+        &mut self,
+        trade_direction: TradeDirection,
+        max_amount_in: u64,
+        amount_out_less_fee: u64,
+    ) -> Result<SwapQuote, EmulatorError> {
+        self.check_swap_approved()?;
+        let quote = self
+            .snapshot()?
+            .swap_base_output(trade_direction, amount_out_less_fee)
+            .ok_or(EmulatorError::ZeroTradingTokens)?;
+        if quote.amount_in > max_amount_in {
+            return Err(EmulatorError::ExceededSlippage);
+        }
+        self.apply_swap(&quote)?;
+        Ok(quote)
+    }
+
+    /// Apply deposit
+    pub fn deposit(
+        &mut self,
+        lp_token_amount: u64,
+        maximum_token_0_amount: u64,
+        maximum_token_1_amount: u64,
+    ) -> Result<DepositQuote, EmulatorError> {
+        if !self.pool_state.pool_status().can_deposit() {
+            return Err(EmulatorError::NotApproved);
+        }
+        if lp_token_amount == 0 {
+            return Err(EmulatorError::InvalidInput);
+        }
+        let quote = LiquidityCalculator::deposit(&self.snapshot()?, lp_token_amount)
+            .ok_or(EmulatorError::ZeroTradingTokens)?;
+        if quote.transfer_token_0_amount() > maximum_token_0_amount
+            || quote.transfer_token_1_amount() > maximum_token_1_amount
+        {
+            return Err(EmulatorError::ExceededSlippage);
+        }
+        let vault_0_amount = checked(self.vault_0_amount.checked_add(quote.token_0_amount))?;
+        let vault_1_amount = checked(self.vault_1_amount.checked_add(quote.token_1_amount))?;
+        let lp_supply = checked(self.pool_state.lp_supply.checked_add(lp_token_amount))?;
+        self.vault_0_amount = vault_0_amount;
+        self.vault_1_amount = vault_1_amount;
+        self.pool_state.lp_supply = lp_supply;
+        self.pool_state.recent_epoch = self.epoch;
+        Ok(quote)
+    }
+
+    /// Apply withdraw
+    pub fn withdraw(
+        &mut self,
+        lp_token_amount: u64,
+        minimum_token_0_amount: u64,
+        minimum_token_1_amount: u64,
+    ) -> Result<WithdrawQuote, EmulatorError> {
+        if !self.pool_state.pool_status().can_withdraw() {
+            return Err(EmulatorError::NotApproved);
+        }
+        if lp_token_amount == 0 || lp_token_amount > self.pool_state.lp_supply {
+            return Err(EmulatorError::InvalidInput);
+        }
+        let quote =
+            LiquidityCalculator::withdraw_from_snapshot(&self.snapshot()?, lp_token_amount, 0)
+                .ok_or(EmulatorError::ZeroTradingTokens)?;
+        if quote.receive_token_0_amount < minimum_token_0_amount
+            || quote.receive_token_1_amount < minimum_token_1_amount
+        {
+            return Err(EmulatorError::ExceededSlippage);
+        }
+        let vault_0_amount = checked(self.vault_0_amount.checked_sub(quote.token_0_amount))?;
+        let vault_1_amount = checked(self.vault_1_amount.checked_sub(quote.token_1_amount))?;
+        let lp_supply = checked(self.pool_state.lp_supply.checked_sub(lp_token_amount))?;
+        self.vault_0_amount = vault_0_amount;
+        self.vault_1_amount = vault_1_amount;
+        self.pool_state.lp_supply = lp_supply;
+        self.pool_state.recent_epoch = self.epoch;
+        Ok(quote)
+    }
+
+    fn check_swap_approved(&self) -> Result<(), EmulatorError> {
+        if !self.pool_state.is_tradable(self.block_timestamp) {
+            return Err(EmulatorError::NotApproved);
+        }
+        Ok(())
+    }
+
+    fn apply_swap(&mut self, quote: &SwapQuote) -> Result<(), EmulatorError> {
+        let result = &quote.swap_result;
+        let amount_in = quote.amount_in - quote.input_transfer_fee;
+        let protocol_fee = checked(u64::try_from(result.protocol_fee).ok())?;
+        let fund_fee = checked(u64::try_from(result.fund_fee).ok())?;
+        let pool_state = &self.pool_state;
+        let (vault_0_amount, vault_1_amount, protocol_fees, fund_fees) = match quote.trade_direction
+        {
+            TradeDirection::ZeroForOne => (
+                checked(self.vault_0_amount.checked_add(amount_in))?,
+                checked(self.vault_1_amount.checked_sub(quote.amount_out))?,
+                checked(pool_state.protocol_fees_token_0.checked_add(protocol_fee))?,
+                checked(pool_state.fund_fees_token_0.checked_add(fund_fee))?,
+            ),
+            TradeDirection::OneForZero => (
+                checked(self.vault_0_amount.checked_sub(quote.amount_out))?,
+                checked(self.vault_1_amount.checked_add(amount_in))?,
+                checked(pool_state.protocol_fees_token_1.checked_add(protocol_fee))?,
+                checked(pool_state.fund_fees_token_1.checked_add(fund_fee))?,
+            ),
+        };
+
+        // the oracle records the price that held before this swap
+        self.observation_state.update_from_vaults(
+            self.block_timestamp,
+            &self.pool_state,
+            self.vault_0_amount,
+            self.vault_1_amount,
+        )?;
+        self.vault_0_amount = vault_0_amount;
+        self.vault_1_amount = vault_1_amount;
+        match quote.trade_direction {
+            TradeDirection::ZeroForOne => {
+                self.pool_state.protocol_fees_token_0 = protocol_fees;
+                self.pool_state.fund_fees_token_0 = fund_fees;
+            }
+            TradeDirection::OneForZero => {
+                self.pool_state.protocol_fees_token_1 = protocol_fees;
+                self.pool_state.fund_fees_token_1 = fund_fees;
+            }
+        }
+        self.pool_state.recent_epoch = self.epoch;
+        Ok(())
+    }
+}
+
+// Overflows are program panics; surface them as rejected input instead
+fn checked(value: Option<u64>) -> Result<u64, EmulatorError> {
+    value.ok_or(EmulatorError::InvalidInput)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::{PoolStatusBitFlag, PoolStatusBitIndex};
+
+    fn emulator() -> PoolEmulator {
+        let pool_state = PoolState {
+            open_time: 100,
+            lp_supply: 1_000_000,
+            ..PoolState::default()
+        };
+        let amm_config = AmmConfig {
+            trade_fee_rate: 2_500,
+            protocol_fee_rate: 120_000,
+            fund_fee_rate: 40_000,
+            ..AmmConfig::default()
+        };
+        PoolEmulator::new(
+            pool_state,
+            amm_config,
+            1_000_000_000,
+            2_000_000_000,
+            None,
+            None,
+        )
+    }
+
+    fn accrued_fees(emulator: &PoolEmulator) -> (u64, u64, u64, u64) {
+        let pool_state = &emulator.pool_state;
+        (
+            pool_state.protocol_fees_token_0,
+            pool_state.fund_fees_token_0,
+            pool_state.protocol_fees_token_1,
+            pool_state.fund_fees_token_1,
+        )
+    }
+
+    // Expected values replay the program's integer math by hand: a 0.25%
+    // trade fee, 12% of it to the protocol and 4% to the fund
+    #[test]
+    fn replays_a_sequence_of_swaps_and_liquidity_changes() {
+        let mut emulator = emulator();
+        emulator.set_clock(7, 100);
+
+        // trade fee 250, protocol fee 30, fund fee 10
+        let quote = emulator
+            .swap_base_input(TradeDirection::ZeroForOne, 100_000, 199_480)
+            .unwrap();
+        assert_eq!(quote.amount_received(), 199_480);
+        assert_eq!(
+            (emulator.vault_0_amount, emulator.vault_1_amount),
+            (1_000_100_000, 1_999_800_520)
+        );
+        assert_eq!(accrued_fees(&emulator), (30, 10, 0, 0));
+        assert_eq!(emulator.pool_state.recent_epoch, 7);
+
+        // the curve takes 99_987 and the trade fee 251 of the input
+        emulator.set_clock(8, 130);
+        let quote = emulator
+            .swap_base_output(TradeDirection::OneForZero, 100_237, 50_000)
+            .unwrap();
+        assert_eq!(quote.amount_in, 100_237);
+        assert_eq!(
+            (emulator.vault_0_amount, emulator.vault_1_amount),
+            (1_000_050_000, 1_999_900_757)
+        );
+        assert_eq!(accrued_fees(&emulator), (30, 10, 30, 10));
+        assert_eq!(emulator.pool_state.recent_epoch, 8);
+        // the second swap came late enough to add an observation
+        assert_eq!({ emulator.observation_state.observation_index }, 1);
+
+        // deposits round the reserves' share up, withdrawals down, and
+        // neither touches the accrued fees
+        let deposit = emulator.deposit(100_000, 100_004_996, 199_990_072).unwrap();
+        assert_eq!(
+            (deposit.token_0_amount, deposit.token_1_amount),
+            (100_004_996, 199_990_072)
+        );
+        assert_eq!(
+            (emulator.vault_0_amount, emulator.vault_1_amount),
+            (1_100_054_996, 2_199_890_829)
+        );
+        assert_eq!(emulator.pool_state.lp_supply, 1_100_000);
+
+        let withdraw = emulator.withdraw(50_000, 50_002_498, 99_995_035).unwrap();
+        assert_eq!(
+            (withdraw.token_0_amount, withdraw.token_1_amount),
+            (50_002_498, 99_995_035)
+        );
+        assert_eq!(
+            (emulator.vault_0_amount, emulator.vault_1_amount),
+            (1_050_052_498, 2_099_895_794)
+        );
+        assert_eq!(emulator.pool_state.lp_supply, 1_050_000);
+        assert_eq!(accrued_fees(&emulator), (30, 10, 30, 10));
+
+        let snapshot = emulator.snapshot().unwrap();
+        assert_eq!(
+            (snapshot.token_0_amount, snapshot.token_1_amount),
+            (1_050_052_458, 2_099_895_754)
+        );
+    }
+
+    #[test]
+    fn rejected_instructions_leave_the_pool_unchanged() {
+        let mut emulator = emulator();
+        emulator.set_clock(0, 100);
+        let assert_rejected = |emulator: &mut PoolEmulator,
+                               apply: &dyn Fn(&mut PoolEmulator) -> Result<(), EmulatorError>,
+                               expected: EmulatorError| {
+            let vaults = (emulator.vault_0_amount, emulator.vault_1_amount);
+            let lp_supply = emulator.pool_state.lp_supply;
+            assert_eq!(apply(emulator), Err(expected));
+            assert_eq!((emulator.vault_0_amount, emulator.vault_1_amount), vaults);
+            assert_eq!(emulator.pool_state.lp_supply, lp_supply);
+            assert_eq!(accrued_fees(emulator), (0, 0, 0, 0));
+        };
+        let swap_in = |amount_in: u64, minimum_amount_out: u64| {
+            move |emulator: &mut PoolEmulator| {
+                emulator
+                    .swap_base_input(TradeDirection::ZeroForOne, amount_in, minimum_amount_out)
+                    .map(|_| ())
+            }
+        };
+
+        assert_rejected(
+            &mut emulator,
+            &swap_in(100_000, 199_481),
+            EmulatorError::ExceededSlippage,
+        );
+        assert_rejected(
+            &mut emulator,
+            &|emulator: &mut PoolEmulator| {
+                emulator
+                    .swap_base_output(TradeDirection::OneForZero, 100_236, 50_000)
+                    .map(|_| ())
+            },
+            EmulatorError::ExceededSlippage,
+        );
+        // the trade fee takes the whole input
+        assert_rejected(
+            &mut emulator,
+            &swap_in(1, 0),
+            EmulatorError::ZeroTradingTokens,
+        );
+        assert_rejected(
+            &mut emulator,
+            &|emulator: &mut PoolEmulator| {
+                emulator
+                    .deposit(100_000, 100_000_000, 199_999_999)
+                    .map(|_| ())
+            },
+            EmulatorError::ExceededSlippage,
+        );
+        assert_rejected(
+            &mut emulator,
+            &|emulator: &mut PoolEmulator| emulator.deposit(0, u64::MAX, u64::MAX).map(|_| ()),
+            EmulatorError::InvalidInput,
+        );
+        assert_rejected(
+            &mut emulator,
+            &|emulator: &mut PoolEmulator| emulator.withdraw(1_000_001, 0, 0).map(|_| ()),
+            EmulatorError::InvalidInput,
+        );
+        assert_rejected(
+            &mut emulator,
+            &|emulator: &mut PoolEmulator| emulator.withdraw(100_000, 100_000_001, 0).map(|_| ()),
+            EmulatorError::ExceededSlippage,
+        );
+
+        // before the pool opens, and once each operation is disabled
+        emulator.set_clock(0, 99);
+        assert_rejected(
+            &mut emulator,
+            &swap_in(100_000, 0),
+            EmulatorError::NotApproved,
+        );
+        emulator.set_clock(0, 100);
+        for operation in PoolStatusBitIndex::ALL {
+            emulator
+                .pool_state
+                .set_status_by_bit(operation, PoolStatusBitFlag::Disable);
+        }
+        assert_rejected(
+            &mut emulator,
+            &swap_in(100_000, 0),
+            EmulatorError::NotApproved,
+        );
+        assert_rejected(
+            &mut emulator,
+            &|emulator: &mut PoolEmulator| {
+                emulator.deposit(100_000, u64::MAX, u64::MAX).map(|_| ())
+            },
+            EmulatorError::NotApproved,
+        );
+        assert_rejected(
+            &mut emulator,
+            &|emulator: &mut PoolEmulator| emulator.withdraw(100_000, 0, 0).map(|_| ()),
+            EmulatorError::NotApproved,
+        );
+    }
+
+    #[test]
+    fn vaults_below_the_accrued_fees_fail() {
+        let mut emulator = emulator();
+        emulator.set_clock(0, 100);
+        emulator.pool_state.protocol_fees_token_1 = 2_000_000_001;
+        assert_eq!(
+            emulator
+                .swap_base_input(TradeDirection::ZeroForOne, 100_000, 0)
+                .map(|_| ()),
+            Err(EmulatorError::PoolState(
+                PoolStateError::VaultBelowAccruedFees {
+                    token_index: 1,
+                    vault_amount: 2_000_000_000,
+                    accrued_fees: 2_000_000_001,
+                }
+            ))
+        );
+    }
+}
@@ -0,0 +1,379 @@
+//! Decoding of the Anchor events the program emits in `Program data:` logs
+
+use crate::amm_config::AmmConfig;
+use crate::calculator::TradeDirection;
+use crate::fees::Fees;
+use crate::logs::ProgramInvocation;
+use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::fmt;
+
+/// Emitted by swap_base_input and swap_base_output. Vault amounts are net of
+/// the protocol and fund fees accrued before the swap.
+#[event]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwapEvent {
+    pub pool_id: Pubkey,
+    pub input_vault_before: u64,
+    pub output_vault_before: u64,
+    /// Amount the input vault received, less the input transfer fee
+    pub input_amount: u64,
+    /// Amount the output vault paid out, output transfer fee included
+    pub output_amount: u64,
+    pub input_transfer_fee: u64,
+    pub output_transfer_fee: u64,
+    /// Whether the swap fixed the input amount
+    pub base_input: bool,
+}
+
+impl SwapEvent {
+    /// Protocol and fund fees the swap accrued on the input token under
+    /// `amm_config`
+    pub fn accrued_fees(&self, amm_config: &AmmConfig) -> Option<(u64, u64)> {
+        let trade_fee =
+            Fees::trading_fee(u128::from(self.input_amount), amm_config.trade_fee_rate)?;
+        let protocol_fee = Fees::protocol_fee(trade_fee, amm_config.protocol_fee_rate)?;
+        let fund_fee = Fees::fund_fee(trade_fee, amm_config.fund_fee_rate)?;
+        Some((
+            u64::try_from(protocol_fee).ok()?,
+            u64::try_from(fund_fee).ok()?,
+        ))
+    }
+
+    /// Input and output vault amounts after the swap, net of accrued fees
+    pub fn reserves_after(&self, amm_config: &AmmConfig) -> Option<(u64, u64)> {
+        let (protocol_fee, fund_fee) = self.accrued_fees(amm_config)?;
+        Some((
+            self.input_vault_before
+                .checked_add(self.input_amount)?
+                .checked_sub(protocol_fee)?
+                .checked_sub(fund_fee)?,
+            self.output_vault_before.checked_sub(self.output_amount)?,
+        ))
+    }
+
+    /// The direction of the swap, told by the pool's token_0 and token_1
+    /// reserves from either before or after it, and whether they are the
+    /// ones from after. `None` if the reserves match neither direction, or
+    /// both.
+    pub fn trade_direction(
+        &self,
+        amm_config: &AmmConfig,
+        reserves: (u64, u64),
+    ) -> Option<(TradeDirection, bool)> {
+        let before = (self.input_vault_before, self.output_vault_before);
+        let after = self.reserves_after(amm_config)?;
+        // equal reserves on both sides match both directions
+        let matching = |(input_reserve, output_reserve): (u64, u64)| match (
+            (input_reserve, output_reserve) == reserves,
+            (output_reserve, input_reserve) == reserves,
+        ) {
+            (true, false) => Some(TradeDirection::ZeroForOne),
+            (false, true) => Some(TradeDirection::OneForZero),
+            _ => None,
+        };
+        match (matching(before), matching(after)) {
+            (Some(before), None) => Some((before, false)),
+            (None, Some(after)) => Some((after, true)),
+            // a swap too small to move the reserves
+            (Some(before), Some(after)) if before == after => Some((before, false)),
+            _ => None,
+        }
+    }
+}
+
+/// Emitted by deposit and withdraw. Vault amounts are net of accrued fees.
+/// The amount of LP tokens minted or burned is not part of the event.
+#[event]
+#[derive(Clone, Debug, PartialEq)]
+pub struct LpChangeEvent {
+    pub pool_id: Pubkey,
+    /// LP supply before the change
+    pub lp_amount_before: u64,
+    pub token_0_vault_before: u64,
+    pub token_1_vault_before: u64,
+    /// Amount the vault received or paid out, transfer fee excluded on
+    /// deposits and included on withdrawals
+    pub token_0_amount: u64,
+    pub token_1_amount: u64,
+    pub token_0_transfer_fee: u64,
+    pub token_1_transfer_fee: u64,
+    /// 0 for a deposit, 1 for a withdrawal
+    pub change_type: u8,
+}
+
+impl LpChangeEvent {
+    pub fn is_deposit(&self) -> bool {
+        self.change_type == 0
+    }
+
+    /// token_0 and token_1 vault amounts after the change, net of accrued
+    /// fees
+    pub fn reserves_after(&self) -> Option<(u64, u64)> {
+        if self.is_deposit() {
+            Some((
+                self.token_0_vault_before.checked_add(self.token_0_amount)?,
+                self.token_1_vault_before.checked_add(self.token_1_amount)?,
+            ))
+        } else {
+            Some((
+                self.token_0_vault_before.checked_sub(self.token_0_amount)?,
+                self.token_1_vault_before.checked_sub(self.token_1_amount)?,
+            ))
+        }
+    }
+}
+
+/// Errors decoding event data
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventDecodeError {
+    /// The `Program data:` payload isn't valid base64
+    InvalidBase64,
+    /// The data is shorter than a discriminator
+    TooShort,
+    /// The discriminator matches none of the program's events
+    UnknownDiscriminator([u8; 8]),
+    /// The discriminator matched but the data doesn't deserialize
+    InvalidEventData,
+}
+
+impl fmt::Display for EventDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventDecodeError::InvalidBase64 => write!(f, "event data is not valid base64"),
+            EventDecodeError::TooShort => {
+                write!(f, "event data is shorter than a discriminator")
+            }
+            EventDecodeError::UnknownDiscriminator(discriminator) => {
+                write!(f, "unknown event discriminator {:?}", discriminator)
+            }
+            EventDecodeError::InvalidEventData => write!(f, "invalid event data"),
+        }
+    }
+}
+
+impl std::error::Error for EventDecodeError {}
+
+/// An event of the program, typed by its discriminator
+#[derive(Clone, Debug, PartialEq)]
+pub enum SegaEvent {
+    Swap(SwapEvent),
+    LpChange(LpChangeEvent),
+}
+
+impl SegaEvent {
+    /// Decode raw event data, discriminator included. Trailing bytes are
+    /// ignored so fields appended by later program versions don't break
+    /// decoding.
+    pub fn decode(data: &[u8]) -> std::result::Result<SegaEvent, EventDecodeError> {
+        let discriminator = data.get(..8).ok_or(EventDecodeError::TooShort)?;
+        let mut payload = &data[8..];
+        if discriminator == SwapEvent::DISCRIMINATOR {
+            SwapEvent::deserialize(&mut payload)
+                .map(SegaEvent::Swap)
+                .map_err(|_| EventDecodeError::InvalidEventData)
+        } else if discriminator == LpChangeEvent::DISCRIMINATOR {
+            LpChangeEvent::deserialize(&mut payload)
+                .map(SegaEvent::LpChange)
+                .map_err(|_| EventDecodeError::InvalidEventData)
+        } else {
+            let mut unknown = [0u8; 8];
+            unknown.copy_from_slice(discriminator);
+            Err(EventDecodeError::UnknownDiscriminator(unknown))
+        }
+    }
+
+    /// Decode the base64 payload of a `Program data:` log line
+    pub fn from_log_data(data: &str) -> std::result::Result<SegaEvent, EventDecodeError> {
+        let data = STANDARD
+            .decode(data.trim())
+            .map_err(|_| EventDecodeError::InvalidBase64)?;
+        SegaEvent::decode(&data)
+    }
+
+    /// The pool the event is about
+    pub fn pool_id(&self) -> Pubkey {
+        match self {
+            SegaEvent::Swap(event) => event.pool_id,
+            SegaEvent::LpChange(event) => event.pool_id,
+        }
+    }
+}
+
+impl ProgramInvocation {
+    /// The events this invocation emitted, skipping payloads that aren't
+    /// events of the program
+    pub fn events(&self) -> Vec<SegaEvent> {
+        self.data
+            .iter()
+            .filter_map(|data| SegaEvent::from_log_data(data).ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `Program data:` payloads laid out field by field after the program's
+    // IDL, not serialized with the structs above: a swap of 1_000_000 token_0
+    // for 2_491_256 token_1, and a deposit of 10_000_001 token_0 and
+    // 25_000_003 token_1, both into pool 0x01..0x20
+    const SWAP_LOG_DATA: &str = "QMbN6CYIceIBAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4fIADKmjsAAAAAAPkClQAAAABAQg8AAAAAAHgDJgAAAAAAAAAAAAAAAAAAAAAAAAAAAAE=";
+    const LP_CHANGE_LOG_DATA: &str = "eaPNyTnadTwBAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4fIIDw+gIAAAAAAMqaOwAAAAAA+QKVAAAAAIGWmAAAAAAAQ3h9AQAAAAAAAAAAAAAAAAAAAAAAAAAAAA==";
+
+    fn pool_id() -> Pubkey {
+        Pubkey::new_from_array(std::array::from_fn(|i| i as u8 + 1))
+    }
+
+    fn amm_config() -> AmmConfig {
+        AmmConfig {
+            trade_fee_rate: 2_500,
+            protocol_fee_rate: 120_000,
+            fund_fee_rate: 40_000,
+            ..AmmConfig::default()
+        }
+    }
+
+    fn swap_event() -> SwapEvent {
+        SwapEvent {
+            pool_id: pool_id(),
+            input_vault_before: 1_000_000_000,
+            output_vault_before: 2_500_000_000,
+            input_amount: 1_000_000,
+            output_amount: 2_491_256,
+            input_transfer_fee: 0,
+            output_transfer_fee: 0,
+            base_input: true,
+        }
+    }
+
+    #[test]
+    fn decodes_a_swap_event_log() {
+        assert_eq!(
+            SwapEvent::DISCRIMINATOR,
+            [64, 198, 205, 232, 38, 8, 113, 226]
+        );
+        assert_eq!(
+            SegaEvent::from_log_data(SWAP_LOG_DATA),
+            Ok(SegaEvent::Swap(swap_event()))
+        );
+    }
+
+    #[test]
+    fn decodes_an_lp_change_event_log() {
+        assert_eq!(
+            LpChangeEvent::DISCRIMINATOR,
+            [121, 163, 205, 201, 57, 218, 117, 60]
+        );
+        let SegaEvent::LpChange(event) = SegaEvent::from_log_data(LP_CHANGE_LOG_DATA).unwrap()
+        else {
+            panic!("not an LP change event");
+        };
+        assert_eq!(
+            event,
+            LpChangeEvent {
+                pool_id: pool_id(),
+                lp_amount_before: 50_000_000,
+                token_0_vault_before: 1_000_000_000,
+                token_1_vault_before: 2_500_000_000,
+                token_0_amount: 10_000_001,
+                token_1_amount: 25_000_003,
+                token_0_transfer_fee: 0,
+                token_1_transfer_fee: 0,
+                change_type: 0,
+            }
+        );
+        assert!(event.is_deposit());
+        assert_eq!(event.reserves_after(), Some((1_010_000_001, 2_525_000_003)));
+    }
+
+    #[test]
+    fn ignores_fields_appended_to_an_event() {
+        let mut data = STANDARD.decode(SWAP_LOG_DATA).unwrap();
+        data.extend_from_slice(&[7; 40]);
+        assert_eq!(
+            SegaEvent::from_log_data(&STANDARD.encode(data)),
+            Ok(SegaEvent::Swap(swap_event()))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_and_foreign_event_data() {
+        let data = STANDARD.decode(LP_CHANGE_LOG_DATA).unwrap();
+        assert_eq!(
+            SegaEvent::decode(&data[..data.len() - 1]),
+            Err(EventDecodeError::InvalidEventData)
+        );
+        assert_eq!(
+            SegaEvent::decode(&data[..7]),
+            Err(EventDecodeError::TooShort)
+        );
+        assert_eq!(
+            SegaEvent::decode(&[0; 16]),
+            Err(EventDecodeError::UnknownDiscriminator([0; 8]))
+        );
+        assert_eq!(
+            SegaEvent::from_log_data("not base64!"),
+            Err(EventDecodeError::InvalidBase64)
+        );
+    }
+
+    #[test]
+    fn trade_direction_from_the_reserves_before_the_swap() {
+        let event = swap_event();
+        assert_eq!(
+            event.trade_direction(&amm_config(), (1_000_000_000, 2_500_000_000)),
+            Some((TradeDirection::ZeroForOne, false))
+        );
+        assert_eq!(
+            event.trade_direction(&amm_config(), (2_500_000_000, 1_000_000_000)),
+            Some((TradeDirection::OneForZero, false))
+        );
+    }
+
+    #[test]
+    fn trade_direction_from_reserves_already_updated_by_the_swap() {
+        let event = swap_event();
+        // 2_500 of trade fee, of which 300 protocol and 100 fund fee
+        // stay in the vault without counting towards the reserves
+        assert_eq!(
+            event.reserves_after(&amm_config()),
+            Some((1_000_999_600, 2_497_508_744))
+        );
+        assert_eq!(
+            event.trade_direction(&amm_config(), (1_000_999_600, 2_497_508_744)),
+            Some((TradeDirection::ZeroForOne, true))
+        );
+        assert_eq!(
+            event.trade_direction(&amm_config(), (2_497_508_744, 1_000_999_600)),
+            Some((TradeDirection::OneForZero, true))
+        );
+    }
+
+    #[test]
+    fn trade_direction_is_none_for_unrelated_reserves() {
+        let event = swap_event();
+        // e.g. reserves cached before a swap that was missed
+        assert_eq!(
+            event.trade_direction(&amm_config(), (999_000_000, 2_502_000_000)),
+            None
+        );
+        // equal reserves match both directions before the swap, but only
+        // one after it
+        let balanced = SwapEvent {
+            output_vault_before: 1_000_000_000,
+            ..swap_event()
+        };
+        let (input_after, output_after) = balanced.reserves_after(&amm_config()).unwrap();
+        assert_eq!(
+            balanced.trade_direction(&amm_config(), (1_000_000_000, 1_000_000_000)),
+            None
+        );
+        assert_eq!(
+            balanced.trade_direction(&amm_config(), (output_after, input_after)),
+            Some((TradeDirection::OneForZero, true))
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Token-2022 extension risk classification for pool mints and vaults
+
+use spl_token_2022::extension::{
+    cpi_guard::CpiGuard, default_account_state::DefaultAccountState, memo_transfer::MemoTransfer,
+    permanent_delegate::PermanentDelegate, transfer_hook::TransferHook, BaseState,
+    BaseStateWithExtensions, ExtensionType,
+};
+use spl_token_2022::solana_program::pubkey::Pubkey;
+use spl_token_2022::state::{Account, AccountState, Mint};
+use std::fmt;
+
+/// How much an extension can affect quoting or swapping through a pool
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ExtensionRisk {
+    /// No effect on quotes or swaps
+    #[default]
+    None,
+    /// Quotes are only right if the extension is accounted for
+    Caution,
+    /// Swaps may fail, or a third party can move or freeze the tokens
+    Dangerous,
+}
+
+impl fmt::Display for ExtensionRisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtensionRisk::None => write!(f, "none"),
+            ExtensionRisk::Caution => write!(f, "caution"),
+            ExtensionRisk::Dangerous => write!(f, "dangerous"),
+        }
+    }
+}
+
+/// An extension found on a mint or token account, with its risk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExtensionFinding {
+    pub extension: ExtensionType,
+    pub risk: ExtensionRisk,
+}
+
+/// Classify every extension initialized on a mint
+pub fn classify_mint_extensions<S: BaseStateWithExtensions<Mint>>(
+    mint: &S,
+) -> Vec<ExtensionFinding> {
+    extension_types(mint)
+        .into_iter()
+        .map(|extension| ExtensionFinding {
+            extension,
+            risk: mint_extension_risk(mint, extension),
+        })
+        .collect()
+}
+
+/// Classify every extension initialized on a token account, such as a pool
+/// vault
+pub fn classify_account_extensions<S: BaseStateWithExtensions<Account>>(
+    account: &S,
+) -> Vec<ExtensionFinding> {
+    extension_types(account)
+        .into_iter()
+        .map(|extension| ExtensionFinding {
+            extension,
+            risk: account_extension_risk(account, extension),
+        })
+        .collect()
+}
+
+/// The highest risk among `findings`
+pub fn max_extension_risk(findings: &[ExtensionFinding]) -> ExtensionRisk {
+    findings
+        .iter()
+        .map(|finding| finding.risk)
+        .max()
+        .unwrap_or_default()
+}
+
+// Unreadable extension data is treated as no extensions; the caller fails
+// to unpack such accounts long before quoting them
+fn extension_types<B: BaseState, S: BaseStateWithExtensions<B>>(state: &S) -> Vec<ExtensionType> {
+    state
+        .get_extension_types()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|extension| *extension != ExtensionType::Uninitialized)
+        .collect()
+}
+
+fn mint_extension_risk<S: BaseStateWithExtensions<Mint>>(
+    mint: &S,
+    extension: ExtensionType,
+) -> ExtensionRisk {
+    match extension {
+        // fees are deducted by the quotes, which must use the current epoch
+        ExtensionType::TransferFeeConfig => ExtensionRisk::Caution,
+        // UI amounts drift from raw amounts over time
+        ExtensionType::InterestBearingConfig | ExtensionType::ScaledUiAmount => {
+            ExtensionRisk::Caution
+        }
+        ExtensionType::NonTransferable => ExtensionRisk::Dangerous,
+        // a hook program can reject or reprice any transfer
+        ExtensionType::TransferHook => match mint.get_extension::<TransferHook>() {
+            Ok(hook) if Option::<Pubkey>::from(hook.program_id).is_none() => ExtensionRisk::None,
+            _ => ExtensionRisk::Dangerous,
+        },
+        // the delegate can transfer or burn the vault balances
+        ExtensionType::PermanentDelegate => match mint.get_extension::<PermanentDelegate>() {
+            Ok(delegate) if Option::<Pubkey>::from(delegate.delegate).is_none() => {
+                ExtensionRisk::None
+            }
+            _ => ExtensionRisk::Dangerous,
+        },
+        // the authority can halt every transfer at any time, so an unpaused
+        // mint is no safer to route through than a paused one
+        ExtensionType::Pausable => ExtensionRisk::Dangerous,
+        // new token accounts, user ones included, start out frozen
+        ExtensionType::DefaultAccountState => match mint.get_extension::<DefaultAccountState>() {
+            Ok(state) if state.state != AccountState::Frozen as u8 => ExtensionRisk::None,
+            _ => ExtensionRisk::Dangerous,
+        },
+        _ => ExtensionRisk::None,
+    }
+}
+
+fn account_extension_risk<S: BaseStateWithExtensions<Account>>(
+    account: &S,
+    extension: ExtensionType,
+) -> ExtensionRisk {
+    match extension {
+        ExtensionType::NonTransferableAccount => ExtensionRisk::Dangerous,
+        // transfers into the vault would need a memo the program never adds
+        ExtensionType::MemoTransfer => match account.get_extension::<MemoTransfer>() {
+            Ok(memo) if !bool::from(memo.require_incoming_transfer_memos) => ExtensionRisk::None,
+            _ => ExtensionRisk::Dangerous,
+        },
+        // the program moves vault tokens through CPI
+        ExtensionType::CpiGuard => match account.get_extension::<CpiGuard>() {
+            Ok(guard) if !bool::from(guard.lock_cpi) => ExtensionRisk::None,
+            _ => ExtensionRisk::Dangerous,
+        },
+        _ => ExtensionRisk::None,
+    }
+}
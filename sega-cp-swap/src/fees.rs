@@ -7,7 +7,7 @@ pub struct Fees {}
 
 fn ceil_div(token_amount: u128, fee_numerator: u128, fee_denominator: u128) -> Option<u128> {
     token_amount
-        .checked_mul(u128::from(fee_numerator))
+        .checked_mul(fee_numerator)
         .unwrap()
         .checked_add(fee_denominator)?
         .checked_sub(1)?
@@ -16,11 +16,9 @@ fn ceil_div(token_amount: u128, fee_numerator: u128, fee_denominator: u128) -> O
 
 /// Helper function for calculating swap fee
 pub fn floor_div(token_amount: u128, fee_numerator: u128, fee_denominator: u128) -> Option<u128> {
-    Some(
-        token_amount
-            .checked_mul(fee_numerator)?
-            .checked_div(fee_denominator)?,
-    )
+    token_amount
+        .checked_mul(fee_numerator)?
+        .checked_div(fee_denominator)
 }
 
 impl Fees {
@@ -0,0 +1,522 @@
+//! Decoding of the program's instructions from their data and accounts
+
+use anchor_lang::prelude::Pubkey;
+use anchor_lang::solana_program::hash::hash;
+use anchor_lang::AnchorDeserialize;
+use std::fmt;
+
+/// Accounts of swap_base_input and swap_base_output, in instruction order.
+/// `SegaSwap` is the same layout preceded by the program id.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SwapAccounts {
+    pub payer: Pubkey,
+    pub authority: Pubkey,
+    pub amm_config: Pubkey,
+    pub pool_state: Pubkey,
+    pub input_token_account: Pubkey,
+    pub output_token_account: Pubkey,
+    pub input_vault: Pubkey,
+    pub output_vault: Pubkey,
+    pub input_token_program: Pubkey,
+    pub output_token_program: Pubkey,
+    pub input_token_mint: Pubkey,
+    pub output_token_mint: Pubkey,
+    pub observation_state: Pubkey,
+}
+
+/// Accounts of deposit and, but for the memo program, withdraw
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LiquidityAccounts {
+    pub owner: Pubkey,
+    pub authority: Pubkey,
+    pub pool_state: Pubkey,
+    pub owner_lp_token: Pubkey,
+    pub token_0_account: Pubkey,
+    pub token_1_account: Pubkey,
+    pub token_0_vault: Pubkey,
+    pub token_1_vault: Pubkey,
+    pub token_program: Pubkey,
+    pub token_program_2022: Pubkey,
+    pub vault_0_mint: Pubkey,
+    pub vault_1_mint: Pubkey,
+    pub lp_mint: Pubkey,
+    /// Only present on withdraw
+    pub memo_program: Option<Pubkey>,
+}
+
+/// Accounts of initialize
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InitializeAccounts {
+    pub creator: Pubkey,
+    pub amm_config: Pubkey,
+    pub authority: Pubkey,
+    pub pool_state: Pubkey,
+    pub token_0_mint: Pubkey,
+    pub token_1_mint: Pubkey,
+    pub lp_mint: Pubkey,
+    pub creator_token_0: Pubkey,
+    pub creator_token_1: Pubkey,
+    pub creator_lp_token: Pubkey,
+    pub token_0_vault: Pubkey,
+    pub token_1_vault: Pubkey,
+    pub create_pool_fee: Pubkey,
+    pub observation_state: Pubkey,
+    pub token_program: Pubkey,
+    pub token_0_program: Pubkey,
+    pub token_1_program: Pubkey,
+    pub associated_token_program: Pubkey,
+    pub system_program: Pubkey,
+    pub rent: Pubkey,
+}
+
+/// An instruction of the program with its arguments and named accounts
+#[derive(Clone, Debug, PartialEq)]
+pub enum SegaInstruction {
+    SwapBaseInput {
+        amount_in: u64,
+        minimum_amount_out: u64,
+        accounts: SwapAccounts,
+    },
+    SwapBaseOutput {
+        max_amount_in: u64,
+        amount_out: u64,
+        accounts: SwapAccounts,
+    },
+    Deposit {
+        lp_token_amount: u64,
+        maximum_token_0_amount: u64,
+        maximum_token_1_amount: u64,
+        accounts: LiquidityAccounts,
+    },
+    Withdraw {
+        lp_token_amount: u64,
+        minimum_token_0_amount: u64,
+        minimum_token_1_amount: u64,
+        accounts: LiquidityAccounts,
+    },
+    Initialize {
+        init_amount_0: u64,
+        init_amount_1: u64,
+        open_time: u64,
+        accounts: InitializeAccounts,
+    },
+}
+
+/// Errors decoding an instruction
+#[derive(Clone, Debug, PartialEq)]
+pub enum InstructionDecodeError {
+    /// The data is shorter than a discriminator
+    TooShort,
+    /// The discriminator matches none of the program's instructions
+    UnknownDiscriminator([u8; 8]),
+    /// An admin instruction of the program, which isn't decoded
+    AdminInstruction(&'static str),
+    /// The discriminator matched but the arguments don't deserialize
+    InvalidInstructionData,
+    /// Fewer accounts than the instruction takes
+    NotEnoughAccounts { expected: usize, found: usize },
+}
+
+impl fmt::Display for InstructionDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstructionDecodeError::TooShort => {
+                write!(f, "instruction data is shorter than a discriminator")
+            }
+            InstructionDecodeError::UnknownDiscriminator(discriminator) => {
+                write!(f, "unknown instruction discriminator {:?}", discriminator)
+            }
+            InstructionDecodeError::AdminInstruction(name) => {
+                write!(f, "admin instruction {}", name)
+            }
+            InstructionDecodeError::InvalidInstructionData => {
+                write!(f, "invalid instruction data")
+            }
+            InstructionDecodeError::NotEnoughAccounts { expected, found } => {
+                write!(f, "expected {} accounts, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InstructionDecodeError {}
+
+impl SegaInstruction {
+    /// Decode an instruction from its data, discriminator included, and its
+    /// account keys in order
+    pub fn decode(data: &[u8], accounts: &[Pubkey]) -> Result<Self, InstructionDecodeError> {
+        let discriminator = data.get(..8).ok_or(InstructionDecodeError::TooShort)?;
+        let mut args = &data[8..];
+        if discriminator == instruction_discriminator("swap_base_input") {
+            let (amount_in, minimum_amount_out) =
+                <(u64, u64)>::deserialize(&mut args).map_err(invalid_data)?;
+            Ok(SegaInstruction::SwapBaseInput {
+                amount_in,
+                minimum_amount_out,
+                accounts: SwapAccounts::from_accounts(accounts)?,
+            })
+        } else if discriminator == instruction_discriminator("swap_base_output") {
+            let (max_amount_in, amount_out) =
+                <(u64, u64)>::deserialize(&mut args).map_err(invalid_data)?;
+            Ok(SegaInstruction::SwapBaseOutput {
+                max_amount_in,
+                amount_out,
+                accounts: SwapAccounts::from_accounts(accounts)?,
+            })
+        } else if discriminator == instruction_discriminator("deposit") {
+            let (lp_token_amount, maximum_token_0_amount, maximum_token_1_amount) =
+                <(u64, u64, u64)>::deserialize(&mut args).map_err(invalid_data)?;
+            Ok(SegaInstruction::Deposit {
+                lp_token_amount,
+                maximum_token_0_amount,
+                maximum_token_1_amount,
+                accounts: LiquidityAccounts::from_accounts(accounts, false)?,
+            })
+        } else if discriminator == instruction_discriminator("withdraw") {
+            let (lp_token_amount, minimum_token_0_amount, minimum_token_1_amount) =
+                <(u64, u64, u64)>::deserialize(&mut args).map_err(invalid_data)?;
+            Ok(SegaInstruction::Withdraw {
+                lp_token_amount,
+                minimum_token_0_amount,
+                minimum_token_1_amount,
+                accounts: LiquidityAccounts::from_accounts(accounts, true)?,
+            })
+        } else if discriminator == instruction_discriminator("initialize") {
+            let (init_amount_0, init_amount_1, open_time) =
+                <(u64, u64, u64)>::deserialize(&mut args).map_err(invalid_data)?;
+            Ok(SegaInstruction::Initialize {
+                init_amount_0,
+                init_amount_1,
+                open_time,
+                accounts: InitializeAccounts::from_accounts(accounts)?,
+            })
+        } else if let Some(name) = ADMIN_INSTRUCTIONS
+            .iter()
+            .find(|name| discriminator == instruction_discriminator(name))
+        {
+            Err(InstructionDecodeError::AdminInstruction(name))
+        } else {
+            let mut unknown = [0u8; 8];
+            unknown.copy_from_slice(discriminator);
+            Err(InstructionDecodeError::UnknownDiscriminator(unknown))
+        }
+    }
+
+    /// Name of the instruction as in the program
+    pub fn name(&self) -> &'static str {
+        match self {
+            SegaInstruction::SwapBaseInput { .. } => "swap_base_input",
+            SegaInstruction::SwapBaseOutput { .. } => "swap_base_output",
+            SegaInstruction::Deposit { .. } => "deposit",
+            SegaInstruction::Withdraw { .. } => "withdraw",
+            SegaInstruction::Initialize { .. } => "initialize",
+        }
+    }
+
+    /// The pool the instruction operates on
+    pub fn pool_state(&self) -> Pubkey {
+        match self {
+            SegaInstruction::SwapBaseInput { accounts, .. }
+            | SegaInstruction::SwapBaseOutput { accounts, .. } => accounts.pool_state,
+            SegaInstruction::Deposit { accounts, .. }
+            | SegaInstruction::Withdraw { accounts, .. } => accounts.pool_state,
+            SegaInstruction::Initialize { accounts, .. } => accounts.pool_state,
+        }
+    }
+}
+
+/// Instructions of the program that only its admins send; they touch no
+/// reserves, so they are recognized but not decoded
+pub const ADMIN_INSTRUCTIONS: &[&str] = &[
+    "create_amm_config",
+    "update_amm_config",
+    "update_pool_status",
+    "collect_protocol_fee",
+    "collect_fund_fee",
+];
+
+/// Anchor discriminator of the instruction `name`, e.g. `swap_base_input`
+pub fn instruction_discriminator(name: &str) -> [u8; 8] {
+    let mut discriminator = [0u8; 8];
+    discriminator.copy_from_slice(&hash(format!("global:{}", name).as_bytes()).to_bytes()[..8]);
+    discriminator
+}
+
+fn invalid_data<E>(_: E) -> InstructionDecodeError {
+    InstructionDecodeError::InvalidInstructionData
+}
+
+fn check_accounts(accounts: &[Pubkey], expected: usize) -> Result<(), InstructionDecodeError> {
+    if accounts.len() < expected {
+        return Err(InstructionDecodeError::NotEnoughAccounts {
+            expected,
+            found: accounts.len(),
+        });
+    }
+    Ok(())
+}
+
+impl SwapAccounts {
+    pub const LEN: usize = 13;
+
+    fn from_accounts(accounts: &[Pubkey]) -> Result<Self, InstructionDecodeError> {
+        check_accounts(accounts, Self::LEN)?;
+        Ok(Self {
+            payer: accounts[0],
+            authority: accounts[1],
+            amm_config: accounts[2],
+            pool_state: accounts[3],
+            input_token_account: accounts[4],
+            output_token_account: accounts[5],
+            input_vault: accounts[6],
+            output_vault: accounts[7],
+            input_token_program: accounts[8],
+            output_token_program: accounts[9],
+            input_token_mint: accounts[10],
+            output_token_mint: accounts[11],
+            observation_state: accounts[12],
+        })
+    }
+}
+
+impl LiquidityAccounts {
+    pub const LEN: usize = 13;
+
+    fn from_accounts(accounts: &[Pubkey], with_memo: bool) -> Result<Self, InstructionDecodeError> {
+        check_accounts(accounts, Self::LEN + usize::from(with_memo))?;
+        Ok(Self {
+            owner: accounts[0],
+            authority: accounts[1],
+            pool_state: accounts[2],
+            owner_lp_token: accounts[3],
+            token_0_account: accounts[4],
+            token_1_account: accounts[5],
+            token_0_vault: accounts[6],
+            token_1_vault: accounts[7],
+            token_program: accounts[8],
+            token_program_2022: accounts[9],
+            vault_0_mint: accounts[10],
+            vault_1_mint: accounts[11],
+            lp_mint: accounts[12],
+            memo_program: with_memo.then(|| accounts[13]),
+        })
+    }
+}
+
+impl InitializeAccounts {
+    pub const LEN: usize = 20;
+
+    fn from_accounts(accounts: &[Pubkey]) -> Result<Self, InstructionDecodeError> {
+        check_accounts(accounts, Self::LEN)?;
+        Ok(Self {
+            creator: accounts[0],
+            amm_config: accounts[1],
+            authority: accounts[2],
+            pool_state: accounts[3],
+            token_0_mint: accounts[4],
+            token_1_mint: accounts[5],
+            lp_mint: accounts[6],
+            creator_token_0: accounts[7],
+            creator_token_1: accounts[8],
+            creator_lp_token: accounts[9],
+            token_0_vault: accounts[10],
+            token_1_vault: accounts[11],
+            create_pool_fee: accounts[12],
+            observation_state: accounts[13],
+            token_program: accounts[14],
+            token_0_program: accounts[15],
+            token_1_program: accounts[16],
+            associated_token_program: accounts[17],
+            system_program: accounts[18],
+            rent: accounts[19],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Discriminators as listed in the program's IDL
+    const SWAP_BASE_INPUT: [u8; 8] = [143, 190, 90, 218, 196, 30, 51, 222];
+    const SWAP_BASE_OUTPUT: [u8; 8] = [55, 217, 98, 86, 163, 74, 180, 173];
+    const DEPOSIT: [u8; 8] = [242, 35, 198, 137, 82, 225, 242, 182];
+    const WITHDRAW: [u8; 8] = [183, 18, 70, 156, 148, 109, 161, 34];
+    const INITIALIZE: [u8; 8] = [175, 175, 109, 31, 13, 152, 155, 237];
+
+    fn data(discriminator: [u8; 8], args: &[u64]) -> Vec<u8> {
+        let mut data = discriminator.to_vec();
+        for arg in args {
+            data.extend_from_slice(&arg.to_le_bytes());
+        }
+        data
+    }
+
+    // Distinct keys 1..=count, so every field's position is checked
+    fn accounts(count: usize) -> Vec<Pubkey> {
+        (1..=count)
+            .map(|i| Pubkey::new_from_array([i as u8; 32]))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_swap_base_input() {
+        let accounts = accounts(SwapAccounts::LEN);
+        let instruction =
+            SegaInstruction::decode(&data(SWAP_BASE_INPUT, &[1_000, 990]), &accounts).unwrap();
+        let SegaInstruction::SwapBaseInput {
+            amount_in,
+            minimum_amount_out,
+            accounts: swap_accounts,
+        } = instruction
+        else {
+            panic!("decoded {:?}", instruction);
+        };
+        assert_eq!((amount_in, minimum_amount_out), (1_000, 990));
+        assert_eq!(swap_accounts.payer, accounts[0]);
+        assert_eq!(swap_accounts.pool_state, accounts[3]);
+        assert_eq!(swap_accounts.input_vault, accounts[6]);
+        assert_eq!(swap_accounts.output_token_mint, accounts[11]);
+        assert_eq!(swap_accounts.observation_state, accounts[12]);
+        assert_eq!(instruction.name(), "swap_base_input");
+        assert_eq!(instruction.pool_state(), accounts[3]);
+    }
+
+    #[test]
+    fn decodes_swap_base_output() {
+        let accounts = accounts(SwapAccounts::LEN);
+        let instruction =
+            SegaInstruction::decode(&data(SWAP_BASE_OUTPUT, &[1_010, 1_000]), &accounts).unwrap();
+        let SegaInstruction::SwapBaseOutput {
+            max_amount_in,
+            amount_out,
+            ..
+        } = instruction
+        else {
+            panic!("decoded {:?}", instruction);
+        };
+        assert_eq!((max_amount_in, amount_out), (1_010, 1_000));
+        assert_eq!(instruction.pool_state(), accounts[3]);
+    }
+
+    #[test]
+    fn decodes_deposit_and_withdraw() {
+        let accounts = accounts(LiquidityAccounts::LEN + 1);
+        let deposit =
+            SegaInstruction::decode(&data(DEPOSIT, &[500, 1_000, 2_000]), &accounts).unwrap();
+        let SegaInstruction::Deposit {
+            lp_token_amount,
+            maximum_token_0_amount,
+            maximum_token_1_amount,
+            accounts: deposit_accounts,
+        } = deposit
+        else {
+            panic!("decoded {:?}", deposit);
+        };
+        assert_eq!(
+            (
+                lp_token_amount,
+                maximum_token_0_amount,
+                maximum_token_1_amount
+            ),
+            (500, 1_000, 2_000)
+        );
+        assert_eq!(deposit_accounts.pool_state, accounts[2]);
+        assert_eq!(deposit_accounts.lp_mint, accounts[12]);
+        assert_eq!(deposit_accounts.memo_program, None);
+
+        let withdraw =
+            SegaInstruction::decode(&data(WITHDRAW, &[500, 990, 1_980]), &accounts).unwrap();
+        let SegaInstruction::Withdraw {
+            lp_token_amount,
+            minimum_token_0_amount,
+            minimum_token_1_amount,
+            accounts: withdraw_accounts,
+        } = withdraw
+        else {
+            panic!("decoded {:?}", withdraw);
+        };
+        assert_eq!(
+            (
+                lp_token_amount,
+                minimum_token_0_amount,
+                minimum_token_1_amount
+            ),
+            (500, 990, 1_980)
+        );
+        assert_eq!(withdraw_accounts.memo_program, Some(accounts[13]));
+    }
+
+    #[test]
+    fn decodes_initialize() {
+        let accounts = accounts(InitializeAccounts::LEN);
+        let instruction = SegaInstruction::decode(
+            &data(INITIALIZE, &[1_000_000, 2_000_000, 1_700_000_000]),
+            &accounts,
+        )
+        .unwrap();
+        let SegaInstruction::Initialize {
+            init_amount_0,
+            init_amount_1,
+            open_time,
+            accounts: initialize_accounts,
+        } = instruction
+        else {
+            panic!("decoded {:?}", instruction);
+        };
+        assert_eq!(
+            (init_amount_0, init_amount_1, open_time),
+            (1_000_000, 2_000_000, 1_700_000_000)
+        );
+        assert_eq!(initialize_accounts.amm_config, accounts[1]);
+        assert_eq!(initialize_accounts.pool_state, accounts[3]);
+        assert_eq!(initialize_accounts.rent, accounts[19]);
+    }
+
+    #[test]
+    fn rejects_short_account_lists() {
+        let cases = [
+            (data(SWAP_BASE_INPUT, &[1, 1]), SwapAccounts::LEN),
+            (data(SWAP_BASE_OUTPUT, &[1, 1]), SwapAccounts::LEN),
+            (data(DEPOSIT, &[1, 1, 1]), LiquidityAccounts::LEN),
+            (data(WITHDRAW, &[1, 1, 1]), LiquidityAccounts::LEN + 1),
+            (data(INITIALIZE, &[1, 1, 1]), InitializeAccounts::LEN),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(
+                SegaInstruction::decode(&data, &accounts(expected - 1)),
+                Err(InstructionDecodeError::NotEnoughAccounts {
+                    expected,
+                    found: expected - 1,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_data() {
+        let accounts = accounts(SwapAccounts::LEN);
+        assert_eq!(
+            SegaInstruction::decode(&SWAP_BASE_INPUT[..7], &accounts),
+            Err(InstructionDecodeError::TooShort)
+        );
+        assert_eq!(
+            SegaInstruction::decode(&data(SWAP_BASE_INPUT, &[1]), &accounts),
+            Err(InstructionDecodeError::InvalidInstructionData)
+        );
+        assert_eq!(
+            SegaInstruction::decode(&[0; 16], &accounts),
+            Err(InstructionDecodeError::UnknownDiscriminator([0; 8]))
+        );
+    }
+
+    #[test]
+    fn recognizes_admin_instructions() {
+        for name in ADMIN_INSTRUCTIONS {
+            assert_eq!(
+                SegaInstruction::decode(&instruction_discriminator(name), &[]),
+                Err(InstructionDecodeError::AdminInstruction(name))
+            );
+        }
+    }
+}
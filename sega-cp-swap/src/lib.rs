@@ -1,18 +1,44 @@
+pub mod account;
 pub mod amm_config;
 pub mod calculator;
 pub mod constant_product;
+pub mod depth;
+pub mod emulator;
+pub mod event;
+pub mod extension;
 pub mod fees;
+pub mod instruction;
+pub mod liquidity;
+pub mod logs;
 mod math;
+pub mod oracle;
 pub mod pool;
+pub mod price;
+pub mod quote;
 pub mod swap;
+pub mod token;
+pub mod ui_amount;
 
+pub use account::*;
 pub use amm_config::*;
 pub use calculator::*;
 pub use constant_product::*;
+pub use depth::*;
+pub use emulator::*;
+pub use event::*;
+pub use extension::*;
 pub use fees::*;
+pub use instruction::*;
+pub use liquidity::*;
+pub use logs::*;
 pub use math::*;
+pub use oracle::*;
 pub use pool::*;
+pub use price::*;
+pub use quote::*;
 pub use swap::*;
+pub use token::*;
+pub use ui_amount::*;
 
 use anchor_lang::prelude::*;
 
@@ -0,0 +1,555 @@
+//! Off-chain quotes for adding and removing liquidity, following the program's
+//! deposit and withdraw
+
+use crate::calculator::{CurveCalculator, RoundDirection, TradeDirection};
+use crate::pool::PoolState;
+use crate::quote::{PoolSnapshot, SwapQuote};
+use crate::token::{get_transfer_fee, get_transfer_inverse_fee};
+use spl_token_2022::extension::transfer_fee::TransferFee;
+
+/// Slippage tolerances are denominated in basis points
+pub const SLIPPAGE_DENOMINATOR_VALUE: u64 = 10_000;
+
+/// Encodes the results of burning LP tokens for both trading tokens
+#[derive(Debug, PartialEq)]
+pub struct WithdrawQuote {
+    /// Amount of LP tokens burned
+    pub lp_token_amount: u64,
+    /// Amount of token_0 transferred out of the vault
+    pub token_0_amount: u64,
+    /// Amount of token_1 transferred out of the vault
+    pub token_1_amount: u64,
+    /// Token-2022 transfer fee withheld from token_0
+    pub token_0_transfer_fee: u64,
+    /// Token-2022 transfer fee withheld from token_1
+    pub token_1_transfer_fee: u64,
+    /// Amount of token_0 the user receives
+    pub receive_token_0_amount: u64,
+    /// Amount of token_1 the user receives
+    pub receive_token_1_amount: u64,
+    /// `minimum_token_0_amount` to pass to the withdraw instruction
+    pub minimum_token_0_amount: u64,
+    /// `minimum_token_1_amount` to pass to the withdraw instruction
+    pub minimum_token_1_amount: u64,
+}
+
+/// Encodes the results of minting LP tokens against both trading tokens
+#[derive(Debug, PartialEq)]
+pub struct DepositQuote {
+    /// Amount of LP tokens minted
+    pub lp_token_amount: u64,
+    /// Amount of token_0 the vault receives
+    pub token_0_amount: u64,
+    /// Amount of token_1 the vault receives
+    pub token_1_amount: u64,
+    /// Token-2022 transfer fee the user pays on top of token_0_amount
+    pub token_0_transfer_fee: u64,
+    /// Token-2022 transfer fee the user pays on top of token_1_amount
+    pub token_1_transfer_fee: u64,
+    // token_0_amount plus its transfer fee, checked when quoting
+    transfer_token_0_amount: u64,
+    // token_1_amount plus its transfer fee, checked when quoting
+    transfer_token_1_amount: u64,
+}
+
+impl DepositQuote {
+    /// Amount of token_0 debited from the user
+    pub fn transfer_token_0_amount(&self) -> u64 {
+        self.transfer_token_0_amount
+    }
+
+    /// Amount of token_1 debited from the user
+    pub fn transfer_token_1_amount(&self) -> u64 {
+        self.transfer_token_1_amount
+    }
+}
+
+/// Encodes a single-sided deposit: a swap of part of the input followed by a
+/// proportional deposit of both sides
+#[derive(Debug, PartialEq)]
+pub struct ZapInQuote {
+    /// Swap of the input token into the other side
+    pub swap: SwapQuote,
+    /// Deposit made with the remaining input and the swap output
+    pub deposit: DepositQuote,
+    /// token_0 left with the user after the deposit
+    pub dust_token_0: u64,
+    /// token_1 left with the user after the deposit
+    pub dust_token_1: u64,
+}
+
+impl ZapInQuote {
+    /// Amount of the input token swapped into the other side
+    pub fn swap_amount(&self) -> u64 {
+        self.swap.amount_in
+    }
+
+    /// Amount of LP tokens minted
+    pub fn lp_token_amount(&self) -> u64 {
+        self.deposit.lp_token_amount
+    }
+    // Dust of both sides in common units, valued at the pool's price
+    fn dust_value(&self, snapshot: &PoolSnapshot) -> u128 {
+        u128::from(self.dust_token_0) * u128::from(snapshot.token_1_amount)
+            + u128::from(self.dust_token_1) * u128::from(snapshot.token_0_amount)
+    }
+}
+
+/// Encodes a single-sided withdraw: a withdraw of both sides followed by a
+/// swap of one side into the other
+#[derive(Debug, PartialEq)]
+pub struct ZapOutQuote {
+    /// Withdraw of both trading tokens
+    pub withdraw: WithdrawQuote,
+    /// Swap of the withdrawn amount of the other side into the target token
+    pub swap: SwapQuote,
+    /// Amount of the target token the user ends with
+    pub amount_out: u64,
+    /// `minimum_amount_out` to pass to the swap instruction
+    pub minimum_swap_amount_out: u64,
+}
+
+/// Calculates liquidity operations against a pool the way the program does.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LiquidityCalculator {}
+
+impl LiquidityCalculator {
+    /// Quote burning `lp_token_amount` LP tokens, given the raw vault balances
+    /// and the current epoch transfer fee of each mint (`None` if the mint has
+    /// no transfer fee). The minimum amounts allow `slippage_bps` of slippage
+    /// on what the user receives.
+    pub fn withdraw(
+        pool_state: &PoolState,
+        vault_0_amount: u64,
+        vault_1_amount: u64,
+        lp_token_amount: u64,
+        token_0_transfer_fee: Option<&TransferFee>,
+        token_1_transfer_fee: Option<&TransferFee>,
+        slippage_bps: u64,
+    ) -> Option<WithdrawQuote> {
+        let (token_0_amount, token_1_amount) = pool_state
+            .checked_vault_amount_without_fee(vault_0_amount, vault_1_amount)
+            .ok()?;
+        let snapshot = PoolSnapshot {
+            token_0_amount,
+            token_1_amount,
+            lp_supply: pool_state.lp_supply,
+            token_0_transfer_fee: token_0_transfer_fee.copied(),
+            token_1_transfer_fee: token_1_transfer_fee.copied(),
+            mint_0_decimals: pool_state.mint_0_decimals,
+            mint_1_decimals: pool_state.mint_1_decimals,
+            ..Default::default()
+        };
+        Self::withdraw_from_snapshot(&snapshot, lp_token_amount, slippage_bps)
+    }
+
+    /// Quote burning `lp_token_amount` LP tokens against a pool snapshot
+    pub fn withdraw_from_snapshot(
+        snapshot: &PoolSnapshot,
+        lp_token_amount: u64,
+        slippage_bps: u64,
+    ) -> Option<WithdrawQuote> {
+        if lp_token_amount == 0 || lp_token_amount > snapshot.lp_supply {
+            return None;
+        }
+        let results = CurveCalculator::lp_tokens_to_trading_tokens(
+            u128::from(lp_token_amount),
+            u128::from(snapshot.lp_supply),
+            u128::from(snapshot.token_0_amount),
+            u128::from(snapshot.token_1_amount),
+            RoundDirection::Floor,
+        )?;
+        if results.token_0_amount == 0 || results.token_1_amount == 0 {
+            return None;
+        }
+        let token_0_amount = u64::try_from(results.token_0_amount)
+            .ok()?
+            .min(snapshot.token_0_amount);
+        let token_1_amount = u64::try_from(results.token_1_amount)
+            .ok()?
+            .min(snapshot.token_1_amount);
+
+        let token_0_transfer_fee =
+            get_transfer_fee(snapshot.token_0_transfer_fee.as_ref(), token_0_amount)?;
+        let token_1_transfer_fee =
+            get_transfer_fee(snapshot.token_1_transfer_fee.as_ref(), token_1_amount)?;
+        let receive_token_0_amount = token_0_amount.checked_sub(token_0_transfer_fee)?;
+        let receive_token_1_amount = token_1_amount.checked_sub(token_1_transfer_fee)?;
+
+        Some(WithdrawQuote {
+            lp_token_amount,
+            token_0_amount,
+            token_1_amount,
+            token_0_transfer_fee,
+            token_1_transfer_fee,
+            receive_token_0_amount,
+            receive_token_1_amount,
+            minimum_token_0_amount: amount_with_slippage(receive_token_0_amount, slippage_bps)?,
+            minimum_token_1_amount: amount_with_slippage(receive_token_1_amount, slippage_bps)?,
+        })
+    }
+
+    /// Quote withdrawing `lp_token_amount` LP tokens into a single token: the
+    /// withdrawn amount of the other side is swapped through the pool, which
+    /// the withdraw has already shrunk. `TradeDirection::OneForZero` ends
+    /// with only token_0. Slippage applies to both the withdraw minimums and
+    /// the swap's minimum output.
+    pub fn zap_out(
+        snapshot: &PoolSnapshot,
+        trade_direction: TradeDirection,
+        lp_token_amount: u64,
+        slippage_bps: u64,
+    ) -> Option<ZapOutQuote> {
+        let withdraw = Self::withdraw_from_snapshot(snapshot, lp_token_amount, slippage_bps)?;
+        let after = snapshot.after_withdraw(&withdraw)?;
+        let (swap_amount_in, target_amount) = match trade_direction {
+            TradeDirection::ZeroForOne => (
+                withdraw.receive_token_0_amount,
+                withdraw.receive_token_1_amount,
+            ),
+            TradeDirection::OneForZero => (
+                withdraw.receive_token_1_amount,
+                withdraw.receive_token_0_amount,
+            ),
+        };
+        let swap = after.swap_base_input(trade_direction, swap_amount_in)?;
+        let amount_received = swap.amount_received();
+        Some(ZapOutQuote {
+            amount_out: target_amount.checked_add(amount_received)?,
+            minimum_swap_amount_out: amount_with_slippage(amount_received, slippage_bps)?,
+            withdraw,
+            swap,
+        })
+    }
+
+    /// Quote minting `lp_token_amount` LP tokens. Like the program, amounts
+    /// are rounded up and the transfer fee is added on top.
+    pub fn deposit(snapshot: &PoolSnapshot, lp_token_amount: u64) -> Option<DepositQuote> {
+        if lp_token_amount == 0 {
+            return None;
+        }
+        let results = CurveCalculator::lp_tokens_to_trading_tokens(
+            u128::from(lp_token_amount),
+            u128::from(snapshot.lp_supply),
+            u128::from(snapshot.token_0_amount),
+            u128::from(snapshot.token_1_amount),
+            RoundDirection::Ceiling,
+        )?;
+        if results.token_0_amount == 0 || results.token_1_amount == 0 {
+            return None;
+        }
+        let token_0_amount = u64::try_from(results.token_0_amount).ok()?;
+        let token_1_amount = u64::try_from(results.token_1_amount).ok()?;
+        let token_0_transfer_fee =
+            get_transfer_inverse_fee(snapshot.token_0_transfer_fee.as_ref(), token_0_amount)?;
+        let token_1_transfer_fee =
+            get_transfer_inverse_fee(snapshot.token_1_transfer_fee.as_ref(), token_1_amount)?;
+        Some(DepositQuote {
+            lp_token_amount,
+            token_0_amount,
+            token_1_amount,
+            token_0_transfer_fee,
+            token_1_transfer_fee,
+            transfer_token_0_amount: token_0_amount.checked_add(token_0_transfer_fee)?,
+            transfer_token_1_amount: token_1_amount.checked_add(token_1_transfer_fee)?,
+        })
+    }
+
+    /// Find the largest deposit the user can pay for with at most
+    /// `maximum_token_0_amount` and `maximum_token_1_amount`.
+    pub fn deposit_for_amounts(
+        snapshot: &PoolSnapshot,
+        maximum_token_0_amount: u64,
+        maximum_token_1_amount: u64,
+    ) -> Option<DepositQuote> {
+        if snapshot.token_0_amount == 0 || snapshot.token_1_amount == 0 {
+            return None;
+        }
+        // Upper bound ignoring rounding: the share each side could buy alone
+        let lp_for_side = |maximum_amount: u64, total_amount: u64| {
+            u128::from(maximum_amount)
+                .checked_mul(u128::from(snapshot.lp_supply))?
+                .checked_div(u128::from(total_amount))
+        };
+        let upper = lp_for_side(maximum_token_0_amount, snapshot.token_0_amount)?.min(lp_for_side(
+            maximum_token_1_amount,
+            snapshot.token_1_amount,
+        )?);
+        let fits = |lp_token_amount: u64| {
+            Self::deposit(snapshot, lp_token_amount).filter(|quote| {
+                quote.transfer_token_0_amount() <= maximum_token_0_amount
+                    && quote.transfer_token_1_amount() <= maximum_token_1_amount
+            })
+        };
+        // Paying for a deposit is monotonic in its size
+        let (mut low, mut high) = (0u64, u64::try_from(upper).unwrap_or(u64::MAX));
+        while low < high {
+            let mid = low + (high - low + 1) / 2;
+            if fits(mid).is_some() {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        fits(low)
+    }
+
+    /// Quote depositing `amount_in` of a single token: part of it is swapped
+    /// through the pool so that what is left and the swap output deposit in
+    /// the pool's ratio, with as little dust as possible.
+    /// `TradeDirection::ZeroForOne` zaps in with token_0.
+    pub fn zap_in(
+        snapshot: &PoolSnapshot,
+        trade_direction: TradeDirection,
+        amount_in: u64,
+    ) -> Option<ZapInQuote> {
+        // Compare what the input side left over and the swap output would
+        // add to the vaults, in the post-swap pool ratio. Swapping more
+        // shrinks the first and grows the second, so the balanced swap
+        // amount can be bisected.
+        let (input_transfer_fee, output_transfer_fee) = snapshot.transfer_fees(trade_direction);
+        let input_side_in_excess = |swap_amount: u64| -> bool {
+            let Some(swap) = snapshot.swap_base_input(trade_direction, swap_amount) else {
+                return true;
+            };
+            let Some(after) = snapshot.after_swap(&swap) else {
+                return false;
+            };
+            let (total_input_amount, total_output_amount) = after.reserves(trade_direction);
+            let net_of_transfer_fee = |amount: u64, transfer_fee: Option<&TransferFee>| {
+                amount.saturating_sub(get_transfer_fee(transfer_fee, amount).unwrap_or(amount))
+            };
+            let input_left = net_of_transfer_fee(amount_in - swap_amount, input_transfer_fee);
+            let output_received = net_of_transfer_fee(swap.amount_received(), output_transfer_fee);
+            u128::from(input_left) * u128::from(total_output_amount)
+                > u128::from(output_received) * u128::from(total_input_amount)
+        };
+        let (mut low, mut high) = (1u64, amount_in.checked_sub(1)?);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if input_side_in_excess(mid) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        // Rounding and transfer fees make the exact optimum land next to
+        // the crossing point, so check its neighbours too.
+        let mut best: Option<ZapInQuote> = None;
+        for swap_amount in low.saturating_sub(2)..=low.saturating_add(2).min(amount_in - 1) {
+            let Some(quote) =
+                Self::zap_in_with_swap(snapshot, trade_direction, amount_in, swap_amount)
+            else {
+                continue;
+            };
+            let is_better = match &best {
+                None => true,
+                Some(best) => {
+                    quote.lp_token_amount() > best.lp_token_amount()
+                        || (quote.lp_token_amount() == best.lp_token_amount()
+                            && quote.dust_value(snapshot) < best.dust_value(snapshot))
+                }
+            };
+            if is_better {
+                best = Some(quote);
+            }
+        }
+        best
+    }
+
+    fn zap_in_with_swap(
+        snapshot: &PoolSnapshot,
+        trade_direction: TradeDirection,
+        amount_in: u64,
+        swap_amount: u64,
+    ) -> Option<ZapInQuote> {
+        let swap = snapshot.swap_base_input(trade_direction, swap_amount)?;
+        let after = snapshot.after_swap(&swap)?;
+        let (token_0_balance, token_1_balance) = match trade_direction {
+            TradeDirection::ZeroForOne => (amount_in - swap_amount, swap.amount_received()),
+            TradeDirection::OneForZero => (swap.amount_received(), amount_in - swap_amount),
+        };
+        let deposit = Self::deposit_for_amounts(&after, token_0_balance, token_1_balance)?;
+        Some(ZapInQuote {
+            dust_token_0: token_0_balance - deposit.transfer_token_0_amount(),
+            dust_token_1: token_1_balance - deposit.transfer_token_1_amount(),
+            swap,
+            deposit,
+        })
+    }
+}
+
+/// Lower `amount` by `slippage_bps`, rounding down
+pub fn amount_with_slippage(amount: u64, slippage_bps: u64) -> Option<u64> {
+    let remaining_bps = SLIPPAGE_DENOMINATOR_VALUE.checked_sub(slippage_bps)?;
+    u64::try_from(
+        u128::from(amount)
+            .checked_mul(u128::from(remaining_bps))?
+            .checked_div(u128::from(SLIPPAGE_DENOMINATOR_VALUE))?,
+    )
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer_fee(transfer_fee_basis_points: u16, maximum_fee: u64) -> TransferFee {
+        TransferFee {
+            epoch: 0.into(),
+            maximum_fee: maximum_fee.into(),
+            transfer_fee_basis_points: transfer_fee_basis_points.into(),
+        }
+    }
+
+    fn snapshot(
+        token_0_transfer_fee: Option<TransferFee>,
+        token_1_transfer_fee: Option<TransferFee>,
+    ) -> PoolSnapshot {
+        PoolSnapshot {
+            token_0_amount: 1_000_003,
+            token_1_amount: 2_999_989,
+            lp_supply: 1_731_001,
+            trade_fee_rate: 2_500,
+            protocol_fee_rate: 120_000,
+            fund_fee_rate: 40_000,
+            token_0_transfer_fee,
+            token_1_transfer_fee,
+            ..PoolSnapshot::default()
+        }
+    }
+
+    // The zap-in the solver should find, by trying every swap amount
+    fn exhaustive_zap_in(
+        snapshot: &PoolSnapshot,
+        trade_direction: TradeDirection,
+        amount_in: u64,
+    ) -> Option<ZapInQuote> {
+        (1..amount_in)
+            .filter_map(|swap_amount| {
+                LiquidityCalculator::zap_in_with_swap(
+                    snapshot,
+                    trade_direction,
+                    amount_in,
+                    swap_amount,
+                )
+            })
+            .max_by(|a, b| {
+                a.lp_token_amount()
+                    .cmp(&b.lp_token_amount())
+                    .then(b.dust_value(snapshot).cmp(&a.dust_value(snapshot)))
+            })
+    }
+
+    fn assert_zap_in_is_optimal(snapshot: &PoolSnapshot) {
+        for trade_direction in [TradeDirection::ZeroForOne, TradeDirection::OneForZero] {
+            for amount_in in [997, 2_048, 6_007] {
+                let quote = LiquidityCalculator::zap_in(snapshot, trade_direction, amount_in)
+                    .expect("zap-in quote");
+                let best = exhaustive_zap_in(snapshot, trade_direction, amount_in).unwrap();
+                assert_eq!(quote.lp_token_amount(), best.lp_token_amount());
+                assert_eq!(quote.dust_value(snapshot), best.dust_value(snapshot));
+            }
+        }
+    }
+
+    #[test]
+    fn zap_in_finds_the_rounding_dust_optimum() {
+        assert_zap_in_is_optimal(&snapshot(None, None));
+    }
+
+    #[test]
+    fn zap_in_finds_the_optimum_with_transfer_fee_mints() {
+        assert_zap_in_is_optimal(&snapshot(Some(transfer_fee(150, u64::MAX)), None));
+        assert_zap_in_is_optimal(&snapshot(None, Some(transfer_fee(300, u64::MAX))));
+        // a low maximum fee caps the fee of all but the smallest transfers
+        assert_zap_in_is_optimal(&snapshot(
+            Some(transfer_fee(500, 7)),
+            Some(transfer_fee(100, 3)),
+        ));
+    }
+
+    #[test]
+    fn zap_in_spends_the_input_on_the_swap_deposit_and_dust() {
+        let snapshot = snapshot(Some(transfer_fee(150, u64::MAX)), None);
+        let quote =
+            LiquidityCalculator::zap_in(&snapshot, TradeDirection::ZeroForOne, 6_007).unwrap();
+        assert_eq!(
+            quote.swap_amount() + quote.deposit.transfer_token_0_amount() + quote.dust_token_0,
+            6_007
+        );
+        assert_eq!(
+            quote.deposit.transfer_token_1_amount() + quote.dust_token_1,
+            quote.swap.amount_received()
+        );
+    }
+
+    #[test]
+    fn deposit_adds_the_transfer_fee_to_the_debited_amounts() {
+        let snapshot = snapshot(
+            Some(transfer_fee(150, u64::MAX)),
+            Some(transfer_fee(300, u64::MAX)),
+        );
+        let quote = LiquidityCalculator::deposit(&snapshot, 10_000).unwrap();
+        assert!(quote.token_0_transfer_fee > 0 && quote.token_1_transfer_fee > 0);
+        assert_eq!(
+            quote.transfer_token_0_amount(),
+            quote.token_0_amount + quote.token_0_transfer_fee
+        );
+        assert_eq!(
+            quote.transfer_token_1_amount(),
+            quote.token_1_amount + quote.token_1_transfer_fee
+        );
+    }
+
+    #[test]
+    fn zap_out_swaps_the_other_side_against_the_withdrawn_pool() {
+        for (token_0_transfer_fee, token_1_transfer_fee) in [
+            (None, None),
+            (
+                Some(transfer_fee(150, u64::MAX)),
+                Some(transfer_fee(300, 5)),
+            ),
+        ] {
+            let snapshot = snapshot(token_0_transfer_fee, token_1_transfer_fee);
+            let quote =
+                LiquidityCalculator::zap_out(&snapshot, TradeDirection::OneForZero, 50_000, 100)
+                    .unwrap();
+            let withdraw =
+                LiquidityCalculator::withdraw_from_snapshot(&snapshot, 50_000, 100).unwrap();
+            let swap = snapshot
+                .after_withdraw(&withdraw)
+                .unwrap()
+                .swap_base_input(TradeDirection::OneForZero, withdraw.receive_token_1_amount)
+                .unwrap();
+            assert_eq!(
+                quote.amount_out,
+                withdraw.receive_token_0_amount + swap.amount_received()
+            );
+            assert_eq!(quote.swap, swap);
+            assert_eq!(
+                quote.minimum_swap_amount_out,
+                amount_with_slippage(swap.amount_received(), 100).unwrap()
+            );
+            // the withdraw shrinks the pool, so the swap gets less than it
+            // would from the untouched one
+            let untouched = snapshot
+                .swap_base_input(TradeDirection::OneForZero, withdraw.receive_token_1_amount)
+                .unwrap();
+            assert!(swap.amount_received() < untouched.amount_received());
+        }
+    }
+
+    #[test]
+    fn zap_out_rejects_more_than_the_lp_supply() {
+        let snapshot = snapshot(None, None);
+        assert!(LiquidityCalculator::zap_out(
+            &snapshot,
+            TradeDirection::ZeroForOne,
+            snapshot.lp_supply + 1,
+            0
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,70 @@
+//! Splits transaction logs into the program's invocations
+
+use anchor_lang::prelude::Pubkey;
+
+/// Logs of one invocation of the program, top level or through CPI
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProgramInvocation {
+    /// Invocation depth, 1 for a top level instruction
+    pub depth: usize,
+    /// Instruction name as logged by Anchor, e.g. `SwapBaseInput`
+    pub instruction: Option<String>,
+    /// Base64 payloads of the `Program data:` lines, i.e. emitted events
+    pub data: Vec<String>,
+    /// Whether the invocation returned successfully
+    pub succeeded: bool,
+}
+
+/// The invocations of the program in `logs`, in execution order. Logs of
+/// programs it calls are left out.
+pub fn parse_program_invocations<S: AsRef<str>>(logs: &[S]) -> Vec<ProgramInvocation> {
+    let program_id = crate::ID.to_string();
+    let mut invocations: Vec<ProgramInvocation> = Vec::new();
+    // program id and, for the program's own frames, its invocation index
+    let mut stack: Vec<(String, Option<usize>)> = Vec::new();
+    for log in logs {
+        let log = log.as_ref();
+        if let Some((program, depth)) = parse_invoke(log) {
+            let index = (program == program_id).then(|| {
+                invocations.push(ProgramInvocation {
+                    depth,
+                    ..ProgramInvocation::default()
+                });
+                invocations.len() - 1
+            });
+            stack.push((program.to_string(), index));
+            continue;
+        }
+        let Some((program, index)) = stack.last() else {
+            continue;
+        };
+        if let Some(rest) = log.strip_prefix("Program ") {
+            if rest.starts_with(program.as_str())
+                && (rest.ends_with(" success") || rest.contains(" failed"))
+            {
+                if let Some(index) = index {
+                    invocations[*index].succeeded = rest.ends_with(" success");
+                }
+                stack.pop();
+                continue;
+            }
+        }
+        let Some(invocation) = index.map(|index| &mut invocations[index]) else {
+            continue;
+        };
+        if let Some(instruction) = log.strip_prefix("Program log: Instruction: ") {
+            invocation.instruction = Some(instruction.to_string());
+        } else if let Some(data) = log.strip_prefix("Program data: ") {
+            invocation.data.push(data.to_string());
+        }
+    }
+    invocations
+}
+
+// "Program <id> invoke [<depth>]"
+fn parse_invoke(log: &str) -> Option<(&str, usize)> {
+    let rest = log.strip_prefix("Program ")?;
+    let (program, depth) = rest.split_once(" invoke [")?;
+    program.parse::<Pubkey>().ok()?;
+    Some((program, depth.strip_suffix(']')?.parse().ok()?))
+}
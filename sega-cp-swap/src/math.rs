@@ -1,3 +1,4 @@
+#![allow(clippy::reversed_empty_ranges)]
 use uint::construct_uint;
 
 construct_uint! {
@@ -20,7 +21,7 @@ impl CheckedCeilDiv for u128 {
         // fail.
         if quotient == 0 {
             // return None;
-            if self.checked_mul(2 as u128)? >= rhs {
+            if self.checked_mul(2_u128)? >= rhs {
                 return Some((1, 0));
             } else {
                 return Some((0, 0));
@@ -0,0 +1,375 @@
+//! Off-chain reader for the pool's price oracle
+
+use crate::math::U256;
+use crate::pool::{Observation, ObservationState, PoolState, PoolStateError, OBSERVATION_NUM, Q32};
+use crate::price::PriceCalculator;
+use anchor_lang::prelude::Pubkey;
+use anchor_lang::Discriminator;
+use rust_decimal::Decimal;
+use std::fmt;
+
+/// Minimum seconds between two observations
+pub const OBSERVATION_UPDATE_DURATION_DEFAULT: u64 = 15;
+
+/// Errors reading prices out of an `ObservationState`
+#[derive(Clone, Debug, PartialEq)]
+pub enum OracleError {
+    /// The account data is not an `ObservationState`
+    InvalidAccountData,
+    /// The oracle has not recorded any observation yet
+    NotInitialized,
+    /// The requested window is zero seconds long
+    ZeroWindow,
+    /// The ring does not reach back far enough for the requested window
+    WindowExceedsHistory {
+        /// Requested window, in seconds
+        window: u64,
+        /// Seconds covered by the stored observations
+        available: u64,
+    },
+}
+
+impl fmt::Display for OracleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OracleError::InvalidAccountData => write!(f, "account is not an observation state"),
+            OracleError::NotInitialized => write!(f, "observation state is not initialized"),
+            OracleError::ZeroWindow => write!(f, "twap window must be longer than zero"),
+            OracleError::WindowExceedsHistory { window, available } => write!(
+                f,
+                "twap window of {}s exceeds the {}s of stored observations",
+                window, available
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OracleError {}
+
+/// Time weighted average prices over a window, Q32.32 in raw token units
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Twap {
+    /// Start of the window
+    pub start_timestamp: u64,
+    /// End of the window, the most recent observation
+    pub end_timestamp: u64,
+    /// Average price of token_0 in token_1, Q32.32
+    pub token_0_price_x32: u128,
+    /// Average price of token_1 in token_0, Q32.32
+    pub token_1_price_x32: u128,
+}
+
+impl Twap {
+    /// Average price of token_0 in token_1, in UI units
+    pub fn token_0_price(&self, mint_0_decimals: u8, mint_1_decimals: u8) -> Option<Decimal> {
+        price_from_x32(self.token_0_price_x32, mint_1_decimals, mint_0_decimals)
+    }
+
+    /// Average price of token_1 in token_0, in UI units
+    pub fn token_1_price(&self, mint_0_decimals: u8, mint_1_decimals: u8) -> Option<Decimal> {
+        price_from_x32(self.token_1_price_x32, mint_0_decimals, mint_1_decimals)
+    }
+
+    /// How far the spot price of the given reserves is from the average
+    /// price of token_0, in bps
+    pub fn spot_deviation_bps(&self, token_0_amount: u64, token_1_amount: u64) -> Option<Decimal> {
+        PriceCalculator::price_impact_bps(
+            self.token_0_price(0, 0)?,
+            PriceCalculator::spot_price(token_0_amount, token_1_amount, 0, 0)?,
+        )
+    }
+}
+
+// A Q32.32 price of quote per base in raw units, rescaled by the decimals
+fn price_from_x32(price_x32: u128, quote_decimals: u8, base_decimals: u8) -> Option<Decimal> {
+    let unit = |decimals: u8| Decimal::try_from_i128_with_scale(1, u32::from(decimals)).ok();
+    Decimal::try_from_i128_with_scale(i128::try_from(price_x32).ok()?, 0)
+        .ok()?
+        .checked_div(Decimal::from(Q32 as u64))?
+        .checked_mul(unit(quote_decimals)?)?
+        .checked_div(unit(base_decimals)?)
+}
+
+impl ObservationState {
+    /// An empty observation account for `pool_id`, as created with the pool
+    pub fn new(pool_id: Pubkey) -> ObservationState {
+        let mut observation_state: ObservationState = bytemuck::Zeroable::zeroed();
+        observation_state.pool_id = pool_id;
+        observation_state
+    }
+
+    /// Record the prices that held since the last observation, the way the
+    /// program does on every swap. The first update only stamps the initial
+    /// observation; later ones within `OBSERVATION_UPDATE_DURATION_DEFAULT`
+    /// seconds of the last observation are dropped.
+    pub fn update(
+        &mut self,
+        block_timestamp: u64,
+        token_0_price_x32: u128,
+        token_1_price_x32: u128,
+    ) {
+        let observation_index = usize::from(self.observation_index);
+        if !self.initialized {
+            self.initialized = true;
+            self.observations[observation_index].block_timestamp = block_timestamp;
+            self.observations[observation_index].cumulative_token_0_price_x32 = 0;
+            self.observations[observation_index].cumulative_token_1_price_x32 = 0;
+        } else {
+            let last_observation = self.observations[observation_index];
+            let delta_time = block_timestamp.saturating_sub(last_observation.block_timestamp);
+            if delta_time < OBSERVATION_UPDATE_DURATION_DEFAULT {
+                return;
+            }
+            let delta_token_0_price_x32 = token_0_price_x32.wrapping_mul(delta_time.into());
+            let delta_token_1_price_x32 = token_1_price_x32.wrapping_mul(delta_time.into());
+            let next_observation_index = if observation_index == OBSERVATION_NUM - 1 {
+                0
+            } else {
+                observation_index + 1
+            };
+            let next_observation = &mut self.observations[next_observation_index];
+            next_observation.block_timestamp = block_timestamp;
+            // cumulative prices only occupy the lower 64 bits, the upper ones
+            // absorb overflow
+            next_observation.cumulative_token_0_price_x32 =
+                { last_observation.cumulative_token_0_price_x32 }
+                    .wrapping_add(delta_token_0_price_x32);
+            next_observation.cumulative_token_1_price_x32 =
+                { last_observation.cumulative_token_1_price_x32 }
+                    .wrapping_add(delta_token_1_price_x32);
+            self.observation_index = next_observation_index as u16;
+        }
+    }
+
+    /// Replay a swap: the program records the pool's price from the vault
+    /// balances before the swap at the swap's block timestamp.
+    pub fn update_from_vaults(
+        &mut self,
+        block_timestamp: u64,
+        pool_state: &PoolState,
+        vault_0_before: u64,
+        vault_1_before: u64,
+    ) -> Result<(), PoolStateError> {
+        let (token_0_price_x32, token_1_price_x32) =
+            pool_state.token_price_x32(vault_0_before, vault_1_before)?;
+        self.update(block_timestamp, token_0_price_x32, token_1_price_x32);
+        Ok(())
+    }
+
+    /// Decode the raw data of an observation account, discriminator included
+    pub fn try_from_account_data(data: &[u8]) -> Result<ObservationState, OracleError> {
+        let size = std::mem::size_of::<ObservationState>();
+        if data.len() < 8 + size || &data[..8] != ObservationState::DISCRIMINATOR {
+            return Err(OracleError::InvalidAccountData);
+        }
+        bytemuck::try_pod_read_unaligned(&data[8..8 + size])
+            .map_err(|_| OracleError::InvalidAccountData)
+    }
+
+    /// Observations from the most recent one backwards, stopping at slots
+    /// that were never written.
+    pub fn observations_newest_first(&self) -> Vec<Observation> {
+        let observations = self.observations;
+        let newest = usize::from(self.observation_index) % OBSERVATION_NUM;
+        let mut ret: Vec<Observation> = Vec::with_capacity(OBSERVATION_NUM);
+        for offset in 0..OBSERVATION_NUM {
+            let observation = observations[(newest + OBSERVATION_NUM - offset) % OBSERVATION_NUM];
+            let block_timestamp = observation.block_timestamp;
+            let is_older = ret
+                .last()
+                .map_or(true, |newer| block_timestamp < { newer.block_timestamp });
+            if block_timestamp == 0 || !is_older {
+                break;
+            }
+            ret.push(observation);
+        }
+        ret
+    }
+
+    /// Time weighted average prices over the `window` seconds ending at the
+    /// most recent observation. The start of the window is interpolated
+    /// between the two observations around it.
+    pub fn twap(&self, window: u64) -> Result<Twap, OracleError> {
+        if !self.initialized {
+            return Err(OracleError::NotInitialized);
+        }
+        if window == 0 {
+            return Err(OracleError::ZeroWindow);
+        }
+        let observations = self.observations_newest_first();
+        let newest = observations.first().ok_or(OracleError::NotInitialized)?;
+        let end_timestamp = newest.block_timestamp;
+        let oldest_timestamp = observations
+            .last()
+            .map_or(end_timestamp, |oldest| oldest.block_timestamp);
+        let available = end_timestamp - oldest_timestamp;
+        if window > available {
+            return Err(OracleError::WindowExceedsHistory { window, available });
+        }
+        let start_timestamp = end_timestamp - window;
+
+        // observations are newest first, so the window starts between the
+        // first older-or-equal observation and the one before it
+        let older_index = observations
+            .iter()
+            .position(|observation| observation.block_timestamp <= start_timestamp)
+            .ok_or(OracleError::WindowExceedsHistory { window, available })?;
+        let older = observations[older_index];
+        let newer = observations[older_index.saturating_sub(1)];
+        let cumulative_at_start = |older_cumulative: u128, newer_cumulative: u128| -> u128 {
+            let elapsed = newer.block_timestamp - older.block_timestamp;
+            if elapsed == 0 {
+                return older_cumulative;
+            }
+            let delta = U256::from(newer_cumulative.wrapping_sub(older_cumulative))
+                * U256::from(start_timestamp - older.block_timestamp)
+                / U256::from(elapsed);
+            older_cumulative.wrapping_add(delta.as_u128())
+        };
+        let start_token_0 = cumulative_at_start(
+            older.cumulative_token_0_price_x32,
+            newer.cumulative_token_0_price_x32,
+        );
+        let start_token_1 = cumulative_at_start(
+            older.cumulative_token_1_price_x32,
+            newer.cumulative_token_1_price_x32,
+        );
+        Ok(Twap {
+            start_timestamp,
+            end_timestamp,
+            token_0_price_x32: newest
+                .cumulative_token_0_price_x32
+                .wrapping_sub(start_token_0)
+                / u128::from(window),
+            token_1_price_x32: newest
+                .cumulative_token_1_price_x32
+                .wrapping_sub(start_token_1)
+                / u128::from(window),
+        })
+    }
+}
+
+impl PoolState {
+    /// Prices recorded by the oracle, Q32.32 in raw token units: token_0 in
+    /// token_1 and token_1 in token_0
+    pub fn token_price_x32(
+        &self,
+        vault_0: u64,
+        vault_1: u64,
+    ) -> Result<(u128, u128), PoolStateError> {
+        let (token_0_amount, token_1_amount) =
+            self.checked_vault_amount_without_fee(vault_0, vault_1)?;
+        if token_0_amount == 0 || token_1_amount == 0 {
+            return Err(PoolStateError::EmptyReserves);
+        }
+        Ok((
+            u128::from(token_1_amount) * Q32 / u128::from(token_0_amount),
+            u128::from(token_0_amount) * Q32 / u128::from(token_1_amount),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u64 = 1_700_000_000;
+    const STEP: u64 = OBSERVATION_UPDATE_DURATION_DEFAULT;
+
+    // An oracle initialized at START and then updated every STEP seconds,
+    // `updates` times. The price of token_0 is 1 for the first 105 updates
+    // and 3 afterwards, the price of token_1 stays at 2.
+    fn oracle(updates: u64) -> ObservationState {
+        let mut observation_state = ObservationState::new(Pubkey::new_unique());
+        observation_state.update(START, Q32, 2 * Q32);
+        for update in 1..=updates {
+            let token_0_price_x32 = if update <= 105 { Q32 } else { 3 * Q32 };
+            observation_state.update(START + update * STEP, token_0_price_x32, 2 * Q32);
+        }
+        observation_state
+    }
+
+    #[test]
+    fn updates_within_the_duration_are_dropped() {
+        let mut observation_state = ObservationState::new(Pubkey::new_unique());
+        observation_state.update(START, Q32, 2 * Q32);
+        assert!(observation_state.initialized);
+        assert_eq!({ observation_state.observation_index }, 0);
+        assert_eq!({ observation_state.observations[0].block_timestamp }, START);
+        assert_eq!(
+            { observation_state.observations[0].cumulative_token_0_price_x32 },
+            0
+        );
+
+        observation_state.update(START + STEP - 1, 5 * Q32, 5 * Q32);
+        assert_eq!({ observation_state.observation_index }, 0);
+
+        observation_state.update(START + STEP, Q32, 2 * Q32);
+        assert_eq!({ observation_state.observation_index }, 1);
+        let observation = observation_state.observations[1];
+        assert_eq!({ observation.block_timestamp }, START + STEP);
+        assert_eq!(
+            { observation.cumulative_token_0_price_x32 },
+            u128::from(STEP) * Q32
+        );
+        assert_eq!(
+            { observation.cumulative_token_1_price_x32 },
+            u128::from(STEP) * 2 * Q32
+        );
+    }
+
+    #[test]
+    fn the_ring_wraps_around_and_keeps_the_newest_observations() {
+        let observation_state = oracle(120);
+        assert_eq!(
+            usize::from(observation_state.observation_index),
+            120 % OBSERVATION_NUM
+        );
+
+        let observations = observation_state.observations_newest_first();
+        assert_eq!(observations.len(), OBSERVATION_NUM);
+        assert_eq!({ observations[0].block_timestamp }, START + 120 * STEP);
+        // slots past the newest one still hold the updates from before the
+        // wrap, the oldest being update 21
+        assert_eq!(
+            { observations[OBSERVATION_NUM - 1].block_timestamp },
+            START + 21 * STEP
+        );
+        assert!(observations
+            .windows(2)
+            .all(|pair| { pair[0].block_timestamp } == { pair[1].block_timestamp } + STEP));
+    }
+
+    #[test]
+    fn twap_spans_the_wrap() {
+        // update 100 landed in slot 0, update 110 is the newest in slot 10
+        let observation_state = oracle(110);
+        assert_eq!({ observation_state.observation_index }, 10);
+
+        // updates 96 to 110: ten steps at 1, five at 3
+        let twap = observation_state.twap(15 * STEP).unwrap();
+        assert_eq!(twap.start_timestamp, START + 95 * STEP);
+        assert_eq!(twap.end_timestamp, START + 110 * STEP);
+        assert_eq!(twap.token_0_price_x32, 25 * Q32 / 15);
+        assert_eq!(twap.token_1_price_x32, 2 * Q32);
+
+        // seven more seconds at 1, interpolated inside update 95's step
+        let twap = observation_state.twap(15 * STEP + 7).unwrap();
+        assert_eq!(twap.start_timestamp, START + 95 * STEP - 7);
+        assert_eq!(
+            twap.token_0_price_x32,
+            (382 * Q32) / u128::from(15 * STEP + 7)
+        );
+        assert_eq!(twap.token_1_price_x32, 2 * Q32);
+
+        let available = (OBSERVATION_NUM as u64 - 1) * STEP;
+        assert!(observation_state.twap(available).is_ok());
+        assert_eq!(
+            observation_state.twap(available + 1),
+            Err(OracleError::WindowExceedsHistory {
+                window: available + 1,
+                available,
+            })
+        );
+    }
+}
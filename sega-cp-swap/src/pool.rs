@@ -1,5 +1,6 @@
 use anchor_lang::prelude::*;
-use std::ops::BitAnd;
+use std::fmt;
+use std::ops::{BitAnd, BitOr, BitXor};
 /// Seed to derive account address and signature
 pub const POOL_SEED: &str = "pool";
 pub const POOL_LP_MINT_SEED: &str = "pool_lp_mint";
@@ -8,18 +9,162 @@ pub const POOL_VAULT_SEED: &str = "pool_vault";
 pub const Q32: u128 = (u32::MAX as u128) + 1; // 2^32
 pub const OBSERVATION_NUM: usize = 100;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PoolStatusBitIndex {
     Deposit,
     Withdraw,
     Swap,
 }
 
-#[derive(PartialEq, Eq)]
+impl PoolStatusBitIndex {
+    pub const ALL: [PoolStatusBitIndex; 3] = [
+        PoolStatusBitIndex::Deposit,
+        PoolStatusBitIndex::Withdraw,
+        PoolStatusBitIndex::Swap,
+    ];
+}
+
+impl fmt::Display for PoolStatusBitIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolStatusBitIndex::Deposit => write!(f, "deposit"),
+            PoolStatusBitIndex::Withdraw => write!(f, "withdraw"),
+            PoolStatusBitIndex::Swap => write!(f, "swap"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PoolStatusBitFlag {
     Enable,
     Disable,
 }
 
+/// Errors deriving a pool's reserves from its accounts
+#[derive(Clone, Debug, PartialEq)]
+pub enum PoolStateError {
+    /// A vault holds less than the protocol and fund fees accrued on it,
+    /// which Token-2022 fee withholding can cause transiently
+    VaultBelowAccruedFees {
+        /// 0 or 1
+        token_index: u8,
+        vault_amount: u64,
+        accrued_fees: u64,
+    },
+    /// The accrued protocol and fund fees overflow a u64
+    AccruedFeesOverflow,
+    /// A reserve is empty, so the pool has no price
+    EmptyReserves,
+}
+
+impl fmt::Display for PoolStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolStateError::VaultBelowAccruedFees {
+                token_index,
+                vault_amount,
+                accrued_fees,
+            } => write!(
+                f,
+                "vault {} holds {} but owes {} in accrued fees",
+                token_index, vault_amount, accrued_fees
+            ),
+            PoolStateError::AccruedFeesOverflow => write!(f, "accrued fees overflow"),
+            PoolStateError::EmptyReserves => write!(f, "pool reserves are empty"),
+        }
+    }
+}
+
+impl std::error::Error for PoolStateError {}
+
+/// The pool's status bits, where a set bit disables an operation
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStatus(u8);
+
+impl PoolStatus {
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Whether `operation` is allowed, i.e. its bit is clear
+    pub fn is_enabled(&self, operation: PoolStatusBitIndex) -> bool {
+        self.0.bitand(Self::mask(operation)) == 0
+    }
+
+    pub fn can_deposit(&self) -> bool {
+        self.is_enabled(PoolStatusBitIndex::Deposit)
+    }
+
+    pub fn can_withdraw(&self) -> bool {
+        self.is_enabled(PoolStatusBitIndex::Withdraw)
+    }
+
+    pub fn can_swap(&self) -> bool {
+        self.is_enabled(PoolStatusBitIndex::Swap)
+    }
+
+    /// Enable or disable `operation`
+    pub fn set(&mut self, operation: PoolStatusBitIndex, flag: PoolStatusBitFlag) {
+        let mask = Self::mask(operation);
+        self.0 = match flag {
+            PoolStatusBitFlag::Disable => self.0.bitor(mask),
+            PoolStatusBitFlag::Enable => self.0.bitand(u8::MAX.bitxor(mask)),
+        };
+    }
+
+    /// Set `operation`'s bit, disabling it
+    pub fn disable(&mut self, operation: PoolStatusBitIndex) {
+        self.set(operation, PoolStatusBitFlag::Disable);
+    }
+
+    /// Clear `operation`'s bit, enabling it
+    pub fn enable(&mut self, operation: PoolStatusBitIndex) {
+        self.set(operation, PoolStatusBitFlag::Enable);
+    }
+
+    /// Operations whose bit is set
+    pub fn disabled_operations(&self) -> Vec<PoolStatusBitIndex> {
+        PoolStatusBitIndex::ALL
+            .into_iter()
+            .filter(|operation| !self.is_enabled(*operation))
+            .collect()
+    }
+
+    fn mask(operation: PoolStatusBitIndex) -> u8 {
+        1u8 << (operation as u8)
+    }
+}
+
+impl From<u8> for PoolStatus {
+    fn from(bits: u8) -> Self {
+        Self(bits)
+    }
+}
+
+impl fmt::Display for PoolStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let disabled = self.disabled_operations();
+        if disabled.is_empty() {
+            return write!(f, "all operations enabled");
+        }
+        let names: Vec<String> = disabled.iter().map(|op| op.to_string()).collect();
+        write!(f, "{} disabled", names.join(", "))
+    }
+}
+
+impl fmt::Debug for PoolStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolStatus")
+            .field("bits", &self.0)
+            .field("disabled", &self.disabled_operations())
+            .finish()
+    }
+}
+
 #[account]
 #[derive(Default, Debug)]
 pub struct PoolState {
@@ -78,22 +223,75 @@ pub struct PoolState {
 }
 
 impl PoolState {
-    pub const LEN: usize = 8 + 10 * 32 + 1 * 5 + 8 * 7 + 8 * 31;
+    pub const LEN: usize = 8 + 10 * 32 + 5 + 8 * 7 + 8 * 31;
+    /// Offsets of fields in the account data, discriminator included, for
+    /// `Memcmp` filters
+    pub const AMM_CONFIG_OFFSET: usize = 8;
+    pub const TOKEN_0_MINT_OFFSET: usize = 8 + 5 * 32;
+    pub const TOKEN_1_MINT_OFFSET: usize = 8 + 6 * 32;
 
+    /// Returns `true` when the operation is enabled, i.e. its bit is clear.
+    /// Prefer `pool_status()` and its `can_*` accessors.
     pub fn get_status_by_bit(&self, bit: PoolStatusBitIndex) -> bool {
-        let status = u8::from(1) << (bit as u8);
-        self.status.bitand(status) == 0
+        self.pool_status().is_enabled(bit)
+    }
+
+    pub fn set_status_by_bit(&mut self, bit: PoolStatusBitIndex, flag: PoolStatusBitFlag) {
+        let mut status = self.pool_status();
+        status.set(bit, flag);
+        self.status = status.bits();
     }
 
+    pub fn pool_status(&self) -> PoolStatus {
+        PoolStatus::from_bits(self.status)
+    }
+
+    /// Whether a swap at unix timestamp `now` is allowed: swaps are enabled
+    /// and the pool has opened
+    pub fn is_tradable(&self, now: u64) -> bool {
+        self.pool_status().can_swap() && now >= self.open_time
+    }
+
+    /// Panics like the program when a vault is below its accrued fees, use
+    /// `checked_vault_amount_without_fee` off-chain.
     pub fn vault_amount_without_fee(&self, vault_0: u64, vault_1: u64) -> (u64, u64) {
-        (
-            vault_0
-                .checked_sub(self.protocol_fees_token_0 + self.fund_fees_token_0)
-                .unwrap(),
-            vault_1
-                .checked_sub(self.protocol_fees_token_1 + self.fund_fees_token_1)
-                .unwrap(),
-        )
+        self.checked_vault_amount_without_fee(vault_0, vault_1)
+            .unwrap()
+    }
+
+    /// Vault balances less the protocol and fund fees accrued on them
+    pub fn checked_vault_amount_without_fee(
+        &self,
+        vault_0: u64,
+        vault_1: u64,
+    ) -> std::result::Result<(u64, u64), PoolStateError> {
+        let without_fee =
+            |token_index: u8, vault_amount: u64, protocol_fees: u64, fund_fees: u64| {
+                let accrued_fees = protocol_fees
+                    .checked_add(fund_fees)
+                    .ok_or(PoolStateError::AccruedFeesOverflow)?;
+                vault_amount.checked_sub(accrued_fees).ok_or(
+                    PoolStateError::VaultBelowAccruedFees {
+                        token_index,
+                        vault_amount,
+                        accrued_fees,
+                    },
+                )
+            };
+        Ok((
+            without_fee(
+                0,
+                vault_0,
+                self.protocol_fees_token_0,
+                self.fund_fees_token_0,
+            )?,
+            without_fee(
+                1,
+                vault_1,
+                self.protocol_fees_token_1,
+                self.fund_fees_token_1,
+            )?,
+        ))
     }
 }
 
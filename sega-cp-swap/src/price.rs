@@ -0,0 +1,168 @@
+//! Decimal-aware prices for pools and swaps
+
+use crate::calculator::{SwapResult, TradeDirection};
+use crate::pool::PoolState;
+use crate::quote::PoolSnapshot;
+use rust_decimal::Decimal;
+
+/// Price impacts are denominated in basis points
+pub const PRICE_IMPACT_BPS_DENOMINATOR: u64 = 10_000;
+
+/// Computes prices in UI units, i.e. raw amounts scaled by mint decimals.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PriceCalculator {}
+
+impl PriceCalculator {
+    /// Convert a raw token amount to UI units
+    pub fn ui_amount(amount: u128, decimals: u8) -> Option<Decimal> {
+        Decimal::try_from_i128_with_scale(i128::try_from(amount).ok()?, u32::from(decimals)).ok()
+    }
+
+    /// Price of token_0 in token_1 implied by exchanging the two amounts
+    pub fn price(
+        token_0_amount: u128,
+        token_1_amount: u128,
+        mint_0_decimals: u8,
+        mint_1_decimals: u8,
+    ) -> Option<Decimal> {
+        Self::ui_amount(token_1_amount, mint_1_decimals)?
+            .checked_div(Self::ui_amount(token_0_amount, mint_0_decimals)?)
+    }
+
+    /// Price of token_0 in token_1, given the reserves of both
+    pub fn spot_price(
+        token_0_amount: u64,
+        token_1_amount: u64,
+        mint_0_decimals: u8,
+        mint_1_decimals: u8,
+    ) -> Option<Decimal> {
+        Self::ui_amount(u128::from(token_1_amount), mint_1_decimals)?.checked_div(Self::ui_amount(
+            u128::from(token_0_amount),
+            mint_0_decimals,
+        )?)
+    }
+
+    /// Price of token_1 in token_0, given the reserves of both
+    pub fn inverse_spot_price(
+        token_0_amount: u64,
+        token_1_amount: u64,
+        mint_0_decimals: u8,
+        mint_1_decimals: u8,
+    ) -> Option<Decimal> {
+        Self::ui_amount(u128::from(token_0_amount), mint_0_decimals)?.checked_div(Self::ui_amount(
+            u128::from(token_1_amount),
+            mint_1_decimals,
+        )?)
+    }
+
+    /// Price of token_0 in token_1 the swap actually executed at, trade fee
+    /// included, so it is comparable with `spot_price` in either direction.
+    pub fn execution_price(
+        swap_result: &SwapResult,
+        trade_direction: TradeDirection,
+        mint_0_decimals: u8,
+        mint_1_decimals: u8,
+    ) -> Option<Decimal> {
+        let (token_0_amount, token_1_amount) = match trade_direction {
+            TradeDirection::ZeroForOne => (
+                swap_result.source_amount_swapped,
+                swap_result.destination_amount_swapped,
+            ),
+            TradeDirection::OneForZero => (
+                swap_result.destination_amount_swapped,
+                swap_result.source_amount_swapped,
+            ),
+        };
+        Self::price(
+            token_0_amount,
+            token_1_amount,
+            mint_0_decimals,
+            mint_1_decimals,
+        )
+    }
+
+    /// How much worse than `spot_price` the `execution_price` is, in bps
+    pub fn price_impact_bps(spot_price: Decimal, execution_price: Decimal) -> Option<Decimal> {
+        execution_price
+            .checked_sub(spot_price)?
+            .abs()
+            .checked_mul(Decimal::from(PRICE_IMPACT_BPS_DENOMINATOR))?
+            .checked_div(spot_price)
+    }
+}
+
+impl PoolState {
+    /// Price of token_0 in token_1, given the raw vault balances
+    pub fn spot_price(&self, vault_0: u64, vault_1: u64) -> Option<Decimal> {
+        let (token_0_amount, token_1_amount) = self
+            .checked_vault_amount_without_fee(vault_0, vault_1)
+            .ok()?;
+        PriceCalculator::spot_price(
+            token_0_amount,
+            token_1_amount,
+            self.mint_0_decimals,
+            self.mint_1_decimals,
+        )
+    }
+
+    /// Price of token_1 in token_0, given the raw vault balances
+    pub fn inverse_spot_price(&self, vault_0: u64, vault_1: u64) -> Option<Decimal> {
+        let (token_0_amount, token_1_amount) = self
+            .checked_vault_amount_without_fee(vault_0, vault_1)
+            .ok()?;
+        PriceCalculator::inverse_spot_price(
+            token_0_amount,
+            token_1_amount,
+            self.mint_0_decimals,
+            self.mint_1_decimals,
+        )
+    }
+}
+
+impl PoolSnapshot {
+    /// Price of token_0 in token_1
+    pub fn spot_price(&self) -> Option<Decimal> {
+        PriceCalculator::spot_price(
+            self.token_0_amount,
+            self.token_1_amount,
+            self.mint_0_decimals,
+            self.mint_1_decimals,
+        )
+    }
+
+    /// Price of token_1 in token_0
+    pub fn inverse_spot_price(&self) -> Option<Decimal> {
+        PriceCalculator::inverse_spot_price(
+            self.token_0_amount,
+            self.token_1_amount,
+            self.mint_0_decimals,
+            self.mint_1_decimals,
+        )
+    }
+
+    /// Execution price of `swap_result`, a swap against this snapshot
+    pub fn execution_price(
+        &self,
+        swap_result: &SwapResult,
+        trade_direction: TradeDirection,
+    ) -> Option<Decimal> {
+        PriceCalculator::execution_price(
+            swap_result,
+            trade_direction,
+            self.mint_0_decimals,
+            self.mint_1_decimals,
+        )
+    }
+
+    /// Price impact of `swap_result`, a swap against this snapshot, in bps
+    pub fn price_impact_bps(
+        &self,
+        swap_result: &SwapResult,
+        trade_direction: TradeDirection,
+    ) -> Option<Decimal> {
+        PriceCalculator::price_impact_bps(
+            self.spot_price()?,
+            self.execution_price(swap_result, trade_direction)?,
+        )
+    }
+}
@@ -0,0 +1,223 @@
+//! Off-chain swap quotes over a snapshot of a pool's reserves and fees
+
+use crate::amm_config::AmmConfig;
+use crate::calculator::{CurveCalculator, SwapResult, TradeDirection};
+use crate::liquidity::WithdrawQuote;
+use crate::pool::{PoolState, PoolStateError};
+use crate::token::{get_transfer_fee, get_transfer_inverse_fee};
+use spl_token_2022::extension::transfer_fee::TransferFee;
+
+/// Everything needed to quote a pool: the reserves the curve trades against,
+/// the config fee rates and the current epoch transfer fee of each mint.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PoolSnapshot {
+    /// Vault 0 balance less the accrued protocol and fund fees
+    pub token_0_amount: u64,
+    /// Vault 1 balance less the accrued protocol and fund fees
+    pub token_1_amount: u64,
+    /// Circulating LP supply
+    pub lp_supply: u64,
+    /// The trade fee, denominated in hundredths of a bip (10^-6)
+    pub trade_fee_rate: u64,
+    /// The protocol fee, as a share of the trade fee
+    pub protocol_fee_rate: u64,
+    /// The fund fee, as a share of the trade fee
+    pub fund_fee_rate: u64,
+    /// Transfer fee of mint 0, `None` if it has no TransferFeeConfig
+    pub token_0_transfer_fee: Option<TransferFee>,
+    /// Transfer fee of mint 1, `None` if it has no TransferFeeConfig
+    pub token_1_transfer_fee: Option<TransferFee>,
+    /// mint0 and mint1 decimals
+    pub mint_0_decimals: u8,
+    pub mint_1_decimals: u8,
+}
+
+/// Encodes a swap as the user sees it, transfer fees included
+#[derive(Debug, PartialEq)]
+pub struct SwapQuote {
+    /// Direction of the swap
+    pub trade_direction: TradeDirection,
+    /// Amount the user transfers in, including the input transfer fee
+    pub amount_in: u64,
+    /// Transfer fee withheld from the input
+    pub input_transfer_fee: u64,
+    /// Amount the vault transfers out, including the output transfer fee
+    pub amount_out: u64,
+    /// Transfer fee withheld from the output
+    pub output_transfer_fee: u64,
+    /// Curve results for the amount that reached the vault
+    pub swap_result: SwapResult,
+}
+
+impl SwapQuote {
+    /// Amount of output token the user receives
+    pub fn amount_received(&self) -> u64 {
+        self.amount_out - self.output_transfer_fee
+    }
+}
+
+impl PoolSnapshot {
+    pub fn new(
+        pool_state: &PoolState,
+        amm_config: &AmmConfig,
+        vault_0_amount: u64,
+        vault_1_amount: u64,
+        token_0_transfer_fee: Option<TransferFee>,
+        token_1_transfer_fee: Option<TransferFee>,
+    ) -> Result<Self, PoolStateError> {
+        let (token_0_amount, token_1_amount) =
+            pool_state.checked_vault_amount_without_fee(vault_0_amount, vault_1_amount)?;
+        Ok(Self {
+            token_0_amount,
+            token_1_amount,
+            lp_supply: pool_state.lp_supply,
+            trade_fee_rate: amm_config.trade_fee_rate,
+            protocol_fee_rate: amm_config.protocol_fee_rate,
+            fund_fee_rate: amm_config.fund_fee_rate,
+            token_0_transfer_fee,
+            token_1_transfer_fee,
+            mint_0_decimals: pool_state.mint_0_decimals,
+            mint_1_decimals: pool_state.mint_1_decimals,
+        })
+    }
+
+    /// Reserves as (input, output) for the given direction
+    pub fn reserves(&self, trade_direction: TradeDirection) -> (u64, u64) {
+        match trade_direction {
+            TradeDirection::ZeroForOne => (self.token_0_amount, self.token_1_amount),
+            TradeDirection::OneForZero => (self.token_1_amount, self.token_0_amount),
+        }
+    }
+
+    /// Transfer fees as (input, output) for the given direction
+    pub fn transfer_fees(
+        &self,
+        trade_direction: TradeDirection,
+    ) -> (Option<&TransferFee>, Option<&TransferFee>) {
+        match trade_direction {
+            TradeDirection::ZeroForOne => (
+                self.token_0_transfer_fee.as_ref(),
+                self.token_1_transfer_fee.as_ref(),
+            ),
+            TradeDirection::OneForZero => (
+                self.token_1_transfer_fee.as_ref(),
+                self.token_0_transfer_fee.as_ref(),
+            ),
+        }
+    }
+
+    /// Quote swapping exactly `amount_in` of the input token, as the
+    /// swap_base_input instruction does.
+    pub fn swap_base_input(
+        &self,
+        trade_direction: TradeDirection,
+        amount_in: u64,
+    ) -> Option<SwapQuote> {
+        let (total_input_token_amount, total_output_token_amount) = self.reserves(trade_direction);
+        let (input_transfer_fee, output_transfer_fee) = self.transfer_fees(trade_direction);
+
+        let input_transfer_fee = get_transfer_fee(input_transfer_fee, amount_in)?;
+        let actual_amount_in = amount_in.saturating_sub(input_transfer_fee);
+        if actual_amount_in == 0 {
+            return None;
+        }
+        let swap_result = CurveCalculator::swap_base_input(
+            u128::from(actual_amount_in),
+            u128::from(total_input_token_amount),
+            u128::from(total_output_token_amount),
+            self.trade_fee_rate,
+            self.protocol_fee_rate,
+            self.fund_fee_rate,
+        )?;
+        let amount_out = u64::try_from(swap_result.destination_amount_swapped).ok()?;
+        let output_transfer_fee = get_transfer_fee(output_transfer_fee, amount_out)?;
+        if amount_out.checked_sub(output_transfer_fee)? == 0 {
+            return None;
+        }
+        Some(SwapQuote {
+            trade_direction,
+            amount_in,
+            input_transfer_fee,
+            amount_out,
+            output_transfer_fee,
+            swap_result,
+        })
+    }
+
+    /// Quote receiving exactly `amount_out_less_fee` of the output token, as
+    /// the swap_base_output instruction does.
+    pub fn swap_base_output(
+        &self,
+        trade_direction: TradeDirection,
+        amount_out_less_fee: u64,
+    ) -> Option<SwapQuote> {
+        let (total_input_token_amount, total_output_token_amount) = self.reserves(trade_direction);
+        let (input_transfer_fee, output_transfer_fee) = self.transfer_fees(trade_direction);
+
+        let output_transfer_fee =
+            get_transfer_inverse_fee(output_transfer_fee, amount_out_less_fee)?;
+        let amount_out = amount_out_less_fee.checked_add(output_transfer_fee)?;
+        if amount_out >= total_output_token_amount {
+            return None;
+        }
+        let swap_result = CurveCalculator::swap_base_output(
+            u128::from(amount_out),
+            u128::from(total_input_token_amount),
+            u128::from(total_output_token_amount),
+            self.trade_fee_rate,
+            self.protocol_fee_rate,
+            self.fund_fee_rate,
+        )?;
+        let source_amount_swapped = u64::try_from(swap_result.source_amount_swapped).ok()?;
+        if source_amount_swapped == 0 {
+            return None;
+        }
+        let input_transfer_fee =
+            get_transfer_inverse_fee(input_transfer_fee, source_amount_swapped)?;
+        Some(SwapQuote {
+            trade_direction,
+            amount_in: source_amount_swapped.checked_add(input_transfer_fee)?,
+            input_transfer_fee,
+            amount_out,
+            output_transfer_fee,
+            swap_result,
+        })
+    }
+
+    /// The snapshot after `quote` has executed. The protocol and fund fees
+    /// stay in the vault but are no longer part of the reserves.
+    pub fn after_swap(&self, quote: &SwapQuote) -> Option<PoolSnapshot> {
+        let result = &quote.swap_result;
+        let new_input_amount = u64::try_from(
+            result
+                .new_swap_source_amount
+                .checked_sub(result.protocol_fee)?
+                .checked_sub(result.fund_fee)?,
+        )
+        .ok()?;
+        let new_output_amount = u64::try_from(result.new_swap_destination_amount).ok()?;
+        let mut snapshot = *self;
+        match quote.trade_direction {
+            TradeDirection::ZeroForOne => {
+                snapshot.token_0_amount = new_input_amount;
+                snapshot.token_1_amount = new_output_amount;
+            }
+            TradeDirection::OneForZero => {
+                snapshot.token_1_amount = new_input_amount;
+                snapshot.token_0_amount = new_output_amount;
+            }
+        }
+        Some(snapshot)
+    }
+
+    /// The snapshot after `quote` has burned LP tokens and paid out both
+    /// sides from the vaults.
+    pub fn after_withdraw(&self, quote: &WithdrawQuote) -> Option<PoolSnapshot> {
+        Some(PoolSnapshot {
+            token_0_amount: self.token_0_amount.checked_sub(quote.token_0_amount)?,
+            token_1_amount: self.token_1_amount.checked_sub(quote.token_1_amount)?,
+            lp_supply: self.lp_supply.checked_sub(quote.lp_token_amount)?,
+            ..*self
+        })
+    }
+}
@@ -0,0 +1,30 @@
+//! Token-2022 transfer fee helpers, mirroring the program's token utils
+use spl_token_2022::extension::transfer_fee::{TransferFee, MAX_FEE_BASIS_POINTS};
+
+/// Calculate the fee withheld when transferring `pre_fee_amount`.
+/// `transfer_fee` is the mint's fee for the current epoch, `None` for mints
+/// without the TransferFeeConfig extension.
+pub fn get_transfer_fee(transfer_fee: Option<&TransferFee>, pre_fee_amount: u64) -> Option<u64> {
+    match transfer_fee {
+        Some(transfer_fee) => transfer_fee.calculate_fee(pre_fee_amount),
+        None => Some(0),
+    }
+}
+
+/// Calculate the fee that must be added to `post_fee_amount` so that the
+/// receiver gets exactly `post_fee_amount` after the transfer.
+pub fn get_transfer_inverse_fee(
+    transfer_fee: Option<&TransferFee>,
+    post_fee_amount: u64,
+) -> Option<u64> {
+    match transfer_fee {
+        Some(transfer_fee) => {
+            if u16::from(transfer_fee.transfer_fee_basis_points) == MAX_FEE_BASIS_POINTS {
+                Some(u64::from(transfer_fee.maximum_fee))
+            } else {
+                transfer_fee.calculate_inverse_fee(post_fee_amount)
+            }
+        }
+        None => Some(0),
+    }
+}
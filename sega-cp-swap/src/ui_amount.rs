@@ -0,0 +1,186 @@
+//! Raw to UI amount conversions for Token-2022 mints whose UI amount is
+//! scaled by the InterestBearingConfig or ScaledUiAmount extension
+
+use crate::calculator::TradeDirection;
+use crate::depth::DepthLevel;
+use crate::price::PriceCalculator;
+use crate::quote::{PoolSnapshot, SwapQuote};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use spl_token_2022::extension::{
+    interest_bearing_mint::InterestBearingConfig, scaled_ui_amount::ScaledUiAmountConfig,
+    BaseStateWithExtensions, StateWithExtensionsOwned,
+};
+use spl_token_2022::state::Mint;
+
+const ONE_IN_BASIS_POINTS: f64 = 10_000.;
+const SECONDS_PER_YEAR: f64 = 60. * 60. * 24. * 365.24;
+
+/// How a mint's raw amounts map to UI amounts at a given timestamp:
+/// `ui_amount = amount * multiplier / 10^decimals`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UiAmountScale {
+    pub decimals: u8,
+    /// Accrued interest or UI multiplier, 1 for plain mints
+    pub multiplier: Decimal,
+}
+
+impl UiAmountScale {
+    /// Scale of a mint without UI amount extensions
+    pub fn from_decimals(decimals: u8) -> Self {
+        Self {
+            decimals,
+            multiplier: Decimal::ONE,
+        }
+    }
+
+    /// Scale of `mint` at `unix_timestamp`, applying the interest accrued up
+    /// to then and the UI multiplier in effect then, as the token program's
+    /// amount_to_ui_amount does.
+    pub fn from_mint(mint: &StateWithExtensionsOwned<Mint>, unix_timestamp: i64) -> Option<Self> {
+        let mut multiplier = 1_f64;
+        if let Ok(config) = mint.get_extension::<InterestBearingConfig>() {
+            multiplier *= interest_multiplier(config, unix_timestamp)?;
+        }
+        if let Ok(config) = mint.get_extension::<ScaledUiAmountConfig>() {
+            let scale = if unix_timestamp >= i64::from(config.new_multiplier_effective_timestamp) {
+                config.new_multiplier
+            } else {
+                config.multiplier
+            };
+            multiplier *= f64::from(scale);
+        }
+        Some(Self {
+            decimals: mint.base.decimals,
+            multiplier: Decimal::from_f64(multiplier)?,
+        })
+    }
+
+    /// Convert a raw token amount to UI units
+    pub fn ui_amount(&self, amount: u128) -> Option<Decimal> {
+        PriceCalculator::ui_amount(amount, self.decimals)?.checked_mul(self.multiplier)
+    }
+
+    /// Convert a price of quote per base computed from plain decimals to one
+    /// in UI units
+    pub fn ui_price(
+        price: Decimal,
+        base_scale: &UiAmountScale,
+        quote_scale: &UiAmountScale,
+    ) -> Option<Decimal> {
+        price
+            .checked_mul(quote_scale.multiplier)?
+            .checked_div(base_scale.multiplier)
+    }
+}
+
+// exp(average rate * elapsed time), before and after the last rate update
+fn interest_multiplier(config: &InterestBearingConfig, unix_timestamp: i64) -> Option<f64> {
+    let exp = |rate: i16, timespan: i64| -> Option<f64> {
+        let numerator = i128::from(rate).checked_mul(i128::from(timespan))? as f64;
+        Some((numerator / SECONDS_PER_YEAR / ONE_IN_BASIS_POINTS).exp())
+    };
+    let pre_update_timespan = i64::from(config.last_update_timestamp)
+        .checked_sub(i64::from(config.initialization_timestamp))?;
+    let post_update_timespan =
+        unix_timestamp.checked_sub(i64::from(config.last_update_timestamp))?;
+    Some(
+        exp(
+            i16::from(config.pre_update_average_rate),
+            pre_update_timespan,
+        )? * exp(i16::from(config.current_rate), post_update_timespan)?,
+    )
+}
+
+/// A swap quote in UI units
+#[derive(Clone, Debug, PartialEq)]
+pub struct UiSwapQuote {
+    /// Amount the user transfers in, including the input transfer fee
+    pub amount_in: Decimal,
+    /// Amount of output token the user receives
+    pub amount_received: Decimal,
+    /// Price of token_0 in token_1 the user gets, fees included
+    pub execution_price: Decimal,
+}
+
+/// A depth level in UI units
+#[derive(Clone, Debug, PartialEq)]
+pub struct UiDepthLevel {
+    /// Amount the user transfers in
+    pub amount_in: Decimal,
+    /// Amount of output token the user receives
+    pub amount_out: Decimal,
+    /// Price of token_0 in token_1 the user gets, fees included
+    pub execution_price: Decimal,
+    /// Distance of `execution_price` from the spot price, in bps. Scaling
+    /// both prices alike leaves it unchanged.
+    pub price_impact_bps: Decimal,
+}
+
+/// UI amount scales of both mints of a pool
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PoolUiScales {
+    pub token_0: UiAmountScale,
+    pub token_1: UiAmountScale,
+}
+
+impl PoolUiScales {
+    /// Scales as (input, output) for the given direction
+    pub fn scales(&self, trade_direction: TradeDirection) -> (&UiAmountScale, &UiAmountScale) {
+        match trade_direction {
+            TradeDirection::ZeroForOne => (&self.token_0, &self.token_1),
+            TradeDirection::OneForZero => (&self.token_1, &self.token_0),
+        }
+    }
+
+    /// Reserves of the snapshot in UI units
+    pub fn reserves(&self, snapshot: &PoolSnapshot) -> Option<(Decimal, Decimal)> {
+        Some((
+            self.token_0
+                .ui_amount(u128::from(snapshot.token_0_amount))?,
+            self.token_1
+                .ui_amount(u128::from(snapshot.token_1_amount))?,
+        ))
+    }
+
+    /// Price of token_0 in token_1 given the snapshot's reserves, in UI
+    /// units
+    pub fn spot_price(&self, snapshot: &PoolSnapshot) -> Option<Decimal> {
+        UiAmountScale::ui_price(snapshot.spot_price()?, &self.token_0, &self.token_1)
+    }
+
+    /// Convert a swap quote to UI units
+    pub fn quote(&self, quote: &SwapQuote) -> Option<UiSwapQuote> {
+        let (input_scale, output_scale) = self.scales(quote.trade_direction);
+        let amount_in = input_scale.ui_amount(u128::from(quote.amount_in))?;
+        let amount_received = output_scale.ui_amount(u128::from(quote.amount_received()))?;
+        let execution_price = match quote.trade_direction {
+            TradeDirection::ZeroForOne => amount_received.checked_div(amount_in)?,
+            TradeDirection::OneForZero => amount_in.checked_div(amount_received)?,
+        };
+        Some(UiSwapQuote {
+            amount_in,
+            amount_received,
+            execution_price,
+        })
+    }
+
+    /// Convert a depth level quoted in `trade_direction` to UI units
+    pub fn depth_level(
+        &self,
+        trade_direction: TradeDirection,
+        level: &DepthLevel,
+    ) -> Option<UiDepthLevel> {
+        let (input_scale, output_scale) = self.scales(trade_direction);
+        Some(UiDepthLevel {
+            amount_in: input_scale.ui_amount(u128::from(level.amount_in))?,
+            amount_out: output_scale.ui_amount(u128::from(level.amount_out))?,
+            execution_price: UiAmountScale::ui_price(
+                level.execution_price,
+                &self.token_0,
+                &self.token_1,
+            )?,
+            price_impact_bps: level.price_impact_bps,
+        })
+    }
+}